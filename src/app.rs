@@ -1,4 +1,6 @@
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+pub const DEFAULT_STATUS: &str = "allocated,assigned";
 
 #[derive(Parser, Debug)]
 #[clap(subcommand_required = true, arg_required_else_help = true)]
@@ -20,10 +22,36 @@ pub struct App {
     /// Output path
     #[arg(short, long, global = true)]
     pub output: Option<Box<str>>,
+
+    /// For MMDB and GeoLite2 CSV sources, match the registered country instead of the country
+    #[arg(long, global = true, default_value = "false")]
+    pub registered_country: bool,
+
+    /// For GeoLite2 CSV sources, path or url of the locations CSV
+    #[arg(long, global = true)]
+    pub locations: Option<Box<str>>,
+
+    /// For RIR delegated stats sources, record statuses to select
+    #[arg(
+        long,
+        global = true,
+        value_delimiter = ',',
+        default_value = DEFAULT_STATUS
+    )]
+    pub status: Vec<Box<str>>,
+
+    /// For geofeed sources, ISO 3166-2 region to select, e.g. CN-BJ
+    #[arg(long, global = true, value_name = "REGION")]
+    pub select_region: Option<Box<str>>,
+
+    /// For BGP table dump sources (bgpdump -m or MRT RIB), origin ASNs to select, e.g. AS4134
+    #[arg(long, global = true, value_delimiter = ',')]
+    pub asn: Vec<Box<str>>,
 }
 
+/// Required except for `apply nftables --flush`, which main checks.
 #[derive(Debug, Args)]
-#[group(required = true, multiple = false)]
+#[group(required = false, multiple = false)]
 pub struct SourceGroup {
     /// Url of the file to download
     #[arg(short, long, global = true)]
@@ -40,6 +68,11 @@ pub enum Commands {
     Generate {
         #[command(subcommand)]
         generate_command: GenerateCommands,
+
+        /// Current state as JSON (`ip -j route show`, `ip -j rule show`, `nft -j list table`),
+        /// only the difference to it is generated. Can be given multiple times
+        #[arg(long)]
+        current: Vec<Box<str>>,
     },
 
     #[command(about = "Convert from one format to another")]
@@ -52,6 +85,16 @@ pub enum Commands {
         output: Box<str>,
     },
 
+    #[command(about = "Apply rules directly to the kernel via netlink")]
+    Apply {
+        #[command(subcommand)]
+        apply_command: ApplyCommands,
+
+        /// Print what would be applied without touching the kernel
+        #[arg(long, default_value = "false")]
+        dry_run: bool,
+    },
+
     #[command(about = "Generate a systemd service unit")]
     Systemd {
         #[command(subcommand)]
@@ -81,6 +124,267 @@ pub enum GenerateCommands {
         #[command(subcommand)]
         iproute2_command: Iproute2Commands,
     },
+
+    #[command(about = "Generate a systemd-networkd .network drop-in")]
+    Networkd {
+        /// Table name or id
+        #[arg(short, long, default_value = "main")]
+        table: Box<str>,
+
+        /// IPv4 Gateway address, or e.g. `_dhcp4`
+        #[arg(short = '4', long)]
+        ipv4_gateway: Box<str>,
+
+        /// IPv6 Gateway address, or e.g. `_ipv6ra`
+        #[arg(short = '6', long)]
+        ipv6_gateway: Box<str>,
+
+        /// Route metric
+        #[arg(long)]
+        metric: Option<u32>,
+
+        /// Also generate a routing policy rule per CIDR
+        #[arg(long, default_value = "false")]
+        rules: bool,
+
+        /// Routing policy rule priority
+        #[arg(long)]
+        priority: Option<u32>,
+    },
+
+    #[command(about = "Generate a policy routing setup script: table, routes and rules")]
+    PolicyRouting {
+        /// Table name
+        #[arg(short, long)]
+        table: Box<str>,
+
+        /// Table id for /etc/iproute2/rt_tables
+        #[arg(long)]
+        table_id: u32,
+
+        /// IPv4 Gateway address, `auto` for the one of the default route
+        #[arg(short = '4', long)]
+        ipv4_gateway: Box<str>,
+
+        /// IPv6 Gateway address, `auto` for the one of the default route
+        #[arg(short = '6', long)]
+        ipv6_gateway: Box<str>,
+
+        /// Route device, `auto` for the one of the default route
+        #[arg(short, long)]
+        dev: Box<str>,
+
+        /// Use a single fwmark rule fed by nftables sets instead of a rule per CIDR
+        #[arg(short = 'm', long)]
+        fwmark: Option<Box<str>>,
+
+        /// nftables table name, for --fwmark
+        #[arg(long, default_value = "tsumugi")]
+        nft_table: Box<str>,
+
+        /// nftables IPv4 set name, for --fwmark
+        #[arg(long, default_value = "ipv4")]
+        ipv4set: Box<str>,
+
+        /// nftables IPv6 set name, for --fwmark
+        #[arg(long, default_value = "ipv6")]
+        ipv6set: Box<str>,
+    },
+
+    #[command(about = "Generate a BIRD 2 configuration: prefix sets and static routes")]
+    Bird {
+        /// IPv4 prefix set name
+        #[arg(long, default_value = "CN_V4")]
+        ipv4_name: Box<str>,
+
+        /// IPv6 prefix set name
+        #[arg(long, default_value = "CN_V6")]
+        ipv6_name: Box<str>,
+
+        /// Static protocol name, the IPv4 and IPv6 ones are suffixed with `4` and `6`
+        #[arg(long, default_value = "tsumugi")]
+        protocol: Box<str>,
+
+        /// IPv4 next hop, e.g. `via 192.0.2.1` or `unreachable`; emits a static protocol
+        #[arg(short = '4', long)]
+        ipv4_nexthop: Option<Box<str>>,
+
+        /// IPv6 next hop, e.g. `via "eth0"` or `blackhole`; emits a static protocol
+        #[arg(short = '6', long)]
+        ipv6_nexthop: Option<Box<str>>,
+    },
+
+    #[command(about = "Generate a FRR vtysh configuration: prefix lists and static routes")]
+    Frr {
+        /// Prefix list name, shared by the IPv4 and IPv6 lists
+        #[arg(short, long, default_value = "CN")]
+        name: Box<str>,
+
+        /// IPv4 next hop, address or interface; emits static routes
+        #[arg(short = '4', long)]
+        ipv4_nexthop: Option<Box<str>>,
+
+        /// IPv6 next hop, address or interface; emits static routes
+        #[arg(short = '6', long)]
+        ipv6_nexthop: Option<Box<str>>,
+
+        /// Table id of the static routes
+        #[arg(short, long)]
+        table: Option<u32>,
+    },
+
+    #[command(about = "Generate a MikroTik RouterOS .rsc script of address lists and routes")]
+    Routeros {
+        /// Address list name
+        #[arg(short, long, default_value = "CN")]
+        list: Box<str>,
+
+        /// Comment of the entries, also used to find the routes to replace
+        #[arg(long, default_value = "tsumugi")]
+        comment: Box<str>,
+
+        /// IPv4 gateway; emits routes
+        #[arg(short = '4', long)]
+        ipv4_gateway: Option<Box<str>>,
+
+        /// IPv6 gateway; emits routes
+        #[arg(short = '6', long)]
+        ipv6_gateway: Option<Box<str>>,
+
+        /// Routing table of the routes
+        #[arg(short, long, default_value = "main")]
+        table: Box<str>,
+    },
+
+    #[command(about = "Generate OpenWrt fw4 UCI ipset sections, or their loadfiles")]
+    Openwrt {
+        /// IPv4 set name
+        #[arg(short = '4', long)]
+        ipv4set: Box<str>,
+
+        /// IPv6 set name
+        #[arg(short = '6', long)]
+        ipv6set: Box<str>,
+
+        /// Directory of the loadfiles, `<dir>/<set>`, instead of inline entries
+        #[arg(long)]
+        loadfile: Option<Box<str>>,
+
+        /// Generate the plain loadfile of this set instead of the UCI sections
+        #[arg(long)]
+        entries: Option<Box<str>>,
+    },
+
+    #[command(about = "Generate a firewalld ipset XML file")]
+    Firewalld {
+        /// Generate the IPv6 ipset instead of the IPv4 one
+        #[arg(long, default_value = "false")]
+        ipv6: bool,
+    },
+
+    #[command(about = "Generate a pf table, or a pfSense/OPNsense URL table alias file")]
+    Pf {
+        /// Table name
+        #[arg(short, long, default_value = "cn")]
+        table: Box<str>,
+
+        /// Generate a plain alias file, one CIDR per line, instead
+        #[arg(long, default_value = "false")]
+        alias: bool,
+    },
+
+    #[command(about = "Generate WireGuard AllowedIPs covering everything but the CIDRs")]
+    Wireguard {
+        /// Also exclude this CIDR, e.g. the endpoint address; can be repeated
+        #[arg(short, long)]
+        exclude: Vec<Box<str>>,
+
+        /// Keep private, loopback, link-local and multicast ranges in AllowedIPs
+        #[arg(long, default_value = "false")]
+        keep_private: bool,
+
+        /// Peer public key; generates a whole [Peer] section
+        #[arg(long)]
+        public_key: Option<Box<str>>,
+
+        /// Peer endpoint, for the [Peer] section
+        #[arg(long, requires = "public_key")]
+        endpoint: Option<Box<str>>,
+    },
+
+    #[command(about = "Generate OpenVPN route directives")]
+    Openvpn {
+        /// IPv4 gateway, an address or a keyword like `net_gateway` or `vpn_gateway`
+        #[arg(short = '4', long, default_value = "net_gateway")]
+        ipv4_gateway: Box<str>,
+
+        /// IPv6 gateway, an address or e.g. `net_gateway_ipv6`; the default gateway if omitted
+        #[arg(short = '6', long)]
+        ipv6_gateway: Option<Box<str>>,
+
+        /// Generate `push "route ..."` lines for a server configuration
+        #[arg(long, default_value = "false")]
+        push: bool,
+    },
+
+    #[command(about = "Generate a tc filter script for `tc -batch`")]
+    Tc {
+        /// Device
+        #[arg(short, long)]
+        dev: Box<str>,
+
+        /// Parent qdisc
+        #[arg(short, long, default_value = "1:")]
+        parent: Box<str>,
+
+        /// Class the matching traffic goes to
+        #[arg(long)]
+        classid: Box<str>,
+
+        /// Priority of the IPv4 filters, the IPv6 ones use the next one
+        #[arg(long, default_value = "1", value_parser = clap::value_parser!(u32).range(1..65535))]
+        prio: u32,
+
+        /// Match the source address instead of the destination, e.g. on an ifb device
+        #[arg(long, default_value = "false")]
+        src: bool,
+
+        /// Use the u32 classifier instead of flower
+        #[arg(long, default_value = "false")]
+        u32: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ApplyCommands {
+    #[command(about = "Update nftables sets via nf_tables netlink")]
+    Nftables {
+        /// Only flush the sets, without reading the source
+        #[arg(long, default_value = "false")]
+        flush: bool,
+
+        /// Table name
+        #[arg(short, long)]
+        table: Box<str>,
+
+        /// IPv4 set name
+        #[arg(short = '4', long)]
+        ipv4set: Box<str>,
+
+        /// IPv6 set name
+        #[arg(short = '6', long)]
+        ipv6set: Box<str>,
+    },
+
+    #[command(
+        about = "Apply iproute2 routes or rules via rtnetlink",
+        mut_subcommand("rule", |rule| rule.about("Add or delete routing policy rules via rtnetlink")),
+        mut_subcommand("route", |route| route.about("Add or delete routes via rtnetlink"))
+    )]
+    Iproute2 {
+        #[command(subcommand)]
+        iproute2_command: Iproute2Commands,
+    },
 }
 
 #[derive(Subcommand, Debug)]
@@ -105,15 +409,15 @@ pub enum Iproute2Commands {
         #[arg(short, long, default_value = "main")]
         table: Box<str>,
 
-        /// IPv4 Gateway address
+        /// IPv4 Gateway address, `auto` for the one of the default route
         #[arg(short = '4', long)]
         ipv4_gateway: Box<str>,
 
-        /// IPv6 Gateway address
+        /// IPv6 Gateway address, `auto` for the one of the default route
         #[arg(short = '6', long)]
         ipv6_gateway: Box<str>,
 
-        /// Route device
+        /// Route device, `auto` for the one of the default route
         #[arg(short, long)]
         dev: Box<str>,
     },
@@ -125,4 +429,51 @@ pub enum ConvertCommands {
     Srs {},
     #[command(about = "Convert from source to *ray geoip.dat")]
     Ray {},
+    #[command(about = "Convert from source to sing-box source rule-set (JSON)")]
+    SrsJson {},
+    #[command(about = "Convert from source to Clash/mihomo ipcidr rule-provider")]
+    Clash {
+        /// Rule-provider format
+        #[arg(long, value_enum, default_value = "yaml")]
+        format: ClashFormat,
+    },
+    #[command(about = "Convert from source to a plain text list, one CIDR per line")]
+    Text {
+        /// Only the IPv4 CIDRs, to split the list into per family files
+        #[arg(long, default_value = "false", conflicts_with = "ipv6")]
+        ipv4: bool,
+
+        /// Only the IPv6 CIDRs, to split the list into per family files
+        #[arg(long, default_value = "false")]
+        ipv6: bool,
+    },
+    #[command(about = "Convert from source to merged start-end ranges, one per line")]
+    Range {},
+    #[command(
+        about = "Convert from source to MaxMind DB, every country of a geoip.dat without --code"
+    )]
+    Mmdb {},
+    #[command(
+        about = "Convert from source to RFC 8805 geofeed, every country of a geoip.dat without --code"
+    )]
+    Geofeed {
+        /// ISO 3166-2 region to tag every entry with, e.g. CN-BJ
+        #[arg(long, value_name = "REGION")]
+        set_region: Option<Box<str>>,
+
+        /// City to tag every entry with
+        #[arg(long, value_name = "CITY")]
+        set_city: Option<Box<str>>,
+    },
+    #[command(about = "Convert from source to CSV: cidr,family,code")]
+    Csv {},
+    #[command(about = "Convert from source to JSON objects with cidr, family and code")]
+    Json {},
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ClashFormat {
+    Yaml,
+    Text,
+    Mrs,
 }
@@ -0,0 +1,407 @@
+use crate::{
+    cidr,
+    generate::{self, ToString as _, PROTOCOL},
+    geoip::Cidr,
+    netlink::{Message, Socket, NLM_F_ACK, NLM_F_CREATE, NLM_F_EXCL, NLM_F_REQUEST},
+};
+use anyhow::{bail, Context as _, Ok, Result};
+
+use std::{
+    ffi::CString,
+    fmt::Write as _,
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+const BATCH_SIZE: usize = 256;
+/// Bytes of set elements per message, the element list is a nested attribute
+/// with a `u16` length.
+const ELEMENTS_PER_MESSAGE_LEN: usize = 32 * 1024;
+const RT_TABLES: &[&str] = &["/etc/iproute2/rt_tables", "/usr/share/iproute2/rt_tables"];
+
+const RTM_NEWROUTE: u16 = 24;
+const RTM_DELROUTE: u16 = 25;
+const RTM_NEWRULE: u16 = 32;
+const RTM_DELRULE: u16 = 33;
+
+const RTA_DST: u16 = 1;
+const RTA_OIF: u16 = 4;
+const RTA_GATEWAY: u16 = 5;
+const RTA_TABLE: u16 = 15;
+
+const FRA_DST: u16 = 1;
+const FRA_TABLE: u16 = 15;
+const FRA_PROTOCOL: u16 = 21;
+
+const RT_SCOPE_UNIVERSE: u8 = 0;
+const RT_SCOPE_NOWHERE: u8 = 255;
+const RTN_UNICAST: u8 = 1;
+const FR_ACT_TO_TBL: u8 = 1;
+
+const NFNL_SUBSYS_NFTABLES: u16 = 10;
+const NFNL_MSG_BATCH_BEGIN: u16 = 0x10;
+const NFNL_MSG_BATCH_END: u16 = 0x11;
+const NFT_MSG_NEWSETELEM: u16 = 12;
+const NFT_MSG_DELSETELEM: u16 = 14;
+
+const NFTA_SET_ELEM_LIST_TABLE: u16 = 1;
+const NFTA_SET_ELEM_LIST_SET: u16 = 2;
+const NFTA_SET_ELEM_LIST_ELEMENTS: u16 = 3;
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_SET_ELEM_KEY: u16 = 1;
+const NFTA_SET_ELEM_FLAGS: u16 = 3;
+const NFTA_DATA_VALUE: u16 = 1;
+
+const NFPROTO_INET: u8 = 1;
+const NFT_SET_ELEM_INTERVAL_END: u32 = 1;
+
+type Operation = (Box<str>, Message);
+type Interval = (Vec<u8>, Option<Vec<u8>>);
+
+fn family(cidr: &Cidr) -> Result<u8> {
+    Ok(match cidr.ip.len() {
+        4 => libc::AF_INET as u8,
+        16 => libc::AF_INET6 as u8,
+        _ => bail!("Invalid IP length"),
+    })
+}
+
+/// Resolves a routing table name the same way `ip` does, by number or via `rt_tables`.
+fn table_id(table: &str) -> Result<u32> {
+    if let std::result::Result::Ok(id) = table.parse() {
+        return Ok(id);
+    }
+    match table {
+        "default" => return Ok(253),
+        "main" => return Ok(254),
+        "local" => return Ok(255),
+        _ => {}
+    }
+    for path in RT_TABLES {
+        let Some(content) = fs::read_to_string(path).ok() else {
+            continue;
+        };
+        for line in content.lines() {
+            let mut fields = line.split_whitespace();
+            if let (Some(id), Some(name)) = (fields.next(), fields.next()) {
+                if name == table && !id.starts_with('#') {
+                    return id
+                        .parse()
+                        .with_context(|| format!("Invalid table id in {}", path));
+                }
+            }
+        }
+    }
+    bail!("Unknown routing table: {}", table)
+}
+
+fn ifindex(dev: &str) -> Result<u32> {
+    let name = CString::new(dev)?;
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => bail!("Unknown device: {}", dev),
+        index => Ok(index),
+    }
+}
+
+fn ipv4_gateway(gateway: &str) -> Result<Ipv4Addr> {
+    gateway
+        .parse()
+        .with_context(|| format!("Invalid IPv4 gateway: {}", gateway))
+}
+
+fn ipv6_gateway(gateway: &str) -> Result<Ipv6Addr> {
+    gateway
+        .parse()
+        .with_context(|| format!("Invalid IPv6 gateway: {}", gateway))
+}
+
+fn report(descriptions: &[Box<str>], results: Vec<std::io::Result<()>>) -> usize {
+    let mut failed = 0;
+    for (description, result) in descriptions.iter().zip(results) {
+        if let Err(err) = result {
+            eprintln!("Failed to {}: {}", description, err);
+            failed += 1;
+        }
+    }
+    failed
+}
+
+fn run(operations: Vec<Operation>, dry_run: bool) -> Result<Box<str>> {
+    if dry_run {
+        let mut plan = String::new();
+        for (description, _) in operations {
+            writeln!(plan, "{}", description)?;
+        }
+        return Ok(plan.into_boxed_str());
+    }
+    let mut socket = Socket::open(libc::NETLINK_ROUTE)?;
+    let (total, mut failed) = (operations.len(), 0);
+    let (descriptions, mut messages): (Vec<Box<str>>, Vec<Message>) =
+        operations.into_iter().unzip();
+    for (descriptions, messages) in descriptions
+        .chunks(BATCH_SIZE)
+        .zip(messages.chunks_mut(BATCH_SIZE))
+    {
+        failed += report(descriptions, socket.batch(messages)?);
+    }
+    if failed != 0 {
+        bail!("{} of {} entries failed", failed, total);
+    }
+    Ok(Box::from(""))
+}
+
+pub fn iproute2rule(
+    cidr_pair: (Vec<Cidr>, Vec<Cidr>),
+    delete: bool,
+    table: &str,
+    dry_run: bool,
+) -> Result<Box<str>> {
+    let (ipv4_cidrs, ipv6_cidrs) = cidr_pair;
+    let id = table_id(table)?;
+    let (ty, flags, action) = if delete {
+        (RTM_DELRULE, NLM_F_REQUEST | NLM_F_ACK, "delete")
+    } else {
+        (
+            RTM_NEWRULE,
+            NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL,
+            "add",
+        )
+    };
+    let mut operations = Vec::new();
+    for elem in ipv4_cidrs.iter().chain(&ipv6_cidrs) {
+        let mut message = Message::new(ty, flags);
+        // struct fib_rule_hdr
+        message
+            .header(&[
+                family(elem)?,
+                elem.prefix as u8,
+                0,
+                0,
+                id.try_into().unwrap_or(0),
+                0,
+                0,
+                if delete { 0 } else { FR_ACT_TO_TBL },
+                0,
+                0,
+                0,
+                0,
+            ])
+            .attr(FRA_TABLE, &id.to_ne_bytes())
+            .attr(FRA_DST, &elem.ip)
+            .attr(FRA_PROTOCOL, &[PROTOCOL]);
+        operations.push((
+            format!("rule {} to {} lookup {}", action, elem.to_string()?, table).into_boxed_str(),
+            message,
+        ));
+    }
+    run(operations, dry_run)
+}
+
+pub fn iproute2route(
+    cidr_pair: (Vec<Cidr>, Vec<Cidr>),
+    delete: bool,
+    table: &str,
+    ipv4_gateway_str: &str,
+    ipv6_gateway_str: &str,
+    dev: &str,
+    dry_run: bool,
+) -> Result<Box<str>> {
+    let (ipv4_cidrs, ipv6_cidrs) = cidr_pair;
+    let id = table_id(table)?;
+    // A dry run only prints the plan, the device does not have to exist yet.
+    let oif = if dry_run { 0 } else { ifindex(dev)? };
+    let ipv4_gateway = if ipv4_cidrs.is_empty() {
+        None
+    } else {
+        Some(IpAddr::V4(ipv4_gateway(ipv4_gateway_str)?))
+    };
+    let ipv6_gateway = if ipv6_cidrs.is_empty() {
+        None
+    } else {
+        Some(IpAddr::V6(ipv6_gateway(ipv6_gateway_str)?))
+    };
+    let (ty, flags, action, protocol, scope, kind) = if delete {
+        (
+            RTM_DELROUTE,
+            NLM_F_REQUEST | NLM_F_ACK,
+            "delete",
+            PROTOCOL,
+            RT_SCOPE_NOWHERE,
+            0,
+        )
+    } else {
+        (
+            RTM_NEWROUTE,
+            NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE | NLM_F_EXCL,
+            "add",
+            PROTOCOL,
+            RT_SCOPE_UNIVERSE,
+            RTN_UNICAST,
+        )
+    };
+    let mut operations = Vec::new();
+    for (elems, gateway) in [(&ipv4_cidrs, ipv4_gateway), (&ipv6_cidrs, ipv6_gateway)] {
+        let Some(gateway) = gateway else {
+            continue;
+        };
+        let gateway_bytes = match gateway {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        for elem in elems {
+            let mut message = Message::new(ty, flags);
+            // struct rtmsg
+            message
+                .header(&[
+                    family(elem)?,
+                    elem.prefix as u8,
+                    0,
+                    0,
+                    id.try_into().unwrap_or(0),
+                    protocol,
+                    scope,
+                    kind,
+                    0,
+                    0,
+                    0,
+                    0,
+                ])
+                .attr(RTA_TABLE, &id.to_ne_bytes())
+                .attr(RTA_DST, &elem.ip)
+                .attr(RTA_GATEWAY, &gateway_bytes)
+                .attr(RTA_OIF, &oif.to_ne_bytes());
+            operations.push((
+                format!(
+                    "route {} table {} {} via {} dev {}",
+                    action,
+                    table,
+                    elem.to_string()?,
+                    gateway,
+                    dev
+                )
+                .into_boxed_str(),
+                message,
+            ));
+        }
+    }
+    run(operations, dry_run)
+}
+
+/// The most bytes one interval takes in an element list: the start and the end
+/// element each nest `NFTA_LIST_ELEM { NFTA_SET_ELEM_KEY { NFTA_DATA_VALUE } }`,
+/// the end element adds `NFTA_SET_ELEM_FLAGS`.
+const fn interval_len(addr_len: usize) -> usize {
+    2 * (3 * 4 + addr_len) + 8
+}
+
+/// Converts CIDRs into the half-open `[start, end)` intervals an nftables interval
+/// set stores, `end` is `None` when the interval runs up to the last address.
+fn intervals(elems: &[Cidr]) -> Result<Vec<Interval>> {
+    Ok(cidr::merge(elems)?
+        .into_iter()
+        .map(|range| {
+            let end = (range.last != range.max_addr()).then(|| range.bytes(range.last + 1));
+            (range.bytes(range.start), end)
+        })
+        .collect())
+}
+
+fn nfgenmsg(family: u8, res_id: u16) -> [u8; 4] {
+    let [hi, lo] = res_id.to_be_bytes();
+    [family, 0, hi, lo]
+}
+
+fn nft_message(ty: u16, flags: u16, table: &str, set: &str) -> Result<Message> {
+    let mut message = Message::new(NFNL_SUBSYS_NFTABLES << 8 | ty, flags);
+    message
+        .header(&nfgenmsg(NFPROTO_INET, 0))
+        .attr(
+            NFTA_SET_ELEM_LIST_TABLE,
+            CString::new(table)?.as_bytes_with_nul(),
+        )
+        .attr(
+            NFTA_SET_ELEM_LIST_SET,
+            CString::new(set)?.as_bytes_with_nul(),
+        );
+    Ok(message)
+}
+
+pub fn nftables(
+    cidr_pair: (Vec<Cidr>, Vec<Cidr>),
+    table: &str,
+    ipv4set: &str,
+    ipv6set: &str,
+    dry_run: bool,
+) -> Result<Box<str>> {
+    let (ipv4_cidrs, ipv6_cidrs) = cidr_pair;
+    let mut operations = Vec::new();
+    for (set, elems, addr_len) in [(ipv4set, &ipv4_cidrs, 4), (ipv6set, &ipv6_cidrs, 16)] {
+        // An element-less DELSETELEM flushes the set, as `nft flush set` does.
+        operations.push((
+            format!("flush set inet {} {}", table, set).into_boxed_str(),
+            nft_message(NFT_MSG_DELSETELEM, NLM_F_REQUEST | NLM_F_ACK, table, set)?,
+        ));
+        for chunk in intervals(elems)?.chunks(ELEMENTS_PER_MESSAGE_LEN / interval_len(addr_len)) {
+            let mut message = nft_message(
+                NFT_MSG_NEWSETELEM,
+                NLM_F_REQUEST | NLM_F_ACK | NLM_F_CREATE,
+                table,
+                set,
+            )?;
+            let elements = message.begin_nested(NFTA_SET_ELEM_LIST_ELEMENTS);
+            for (start, end) in chunk {
+                let elem = message.begin_nested(NFTA_LIST_ELEM);
+                let key = message.begin_nested(NFTA_SET_ELEM_KEY);
+                message
+                    .attr(NFTA_DATA_VALUE, start)
+                    .end_nested(key)?
+                    .end_nested(elem)?;
+                if let Some(end) = end {
+                    let elem = message.begin_nested(NFTA_LIST_ELEM);
+                    let key = message.begin_nested(NFTA_SET_ELEM_KEY);
+                    message
+                        .attr(NFTA_DATA_VALUE, end)
+                        .end_nested(key)?
+                        .attr(
+                            NFTA_SET_ELEM_FLAGS,
+                            &NFT_SET_ELEM_INTERVAL_END.to_be_bytes(),
+                        )
+                        .end_nested(elem)?;
+                }
+            }
+            message.end_nested(elements)?;
+            operations.push((
+                format!(
+                    "add {} intervals to set inet {} {}",
+                    chunk.len(),
+                    table,
+                    set
+                )
+                .into_boxed_str(),
+                message,
+            ));
+        }
+    }
+    if dry_run {
+        return generate::nftables((ipv4_cidrs, ipv6_cidrs), table, ipv4set, ipv6set);
+    }
+
+    // nf_tables only commits changes wrapped in a batch, all or nothing.
+    let mut begin = Message::new(NFNL_MSG_BATCH_BEGIN, NLM_F_REQUEST);
+    begin.header(&nfgenmsg(libc::AF_UNSPEC as u8, NFNL_SUBSYS_NFTABLES));
+    let mut end = Message::new(NFNL_MSG_BATCH_END, NLM_F_REQUEST);
+    end.header(&nfgenmsg(libc::AF_UNSPEC as u8, NFNL_SUBSYS_NFTABLES));
+    let (mut descriptions, mut messages): (Vec<Box<str>>, Vec<Message>) =
+        operations.into_iter().unzip();
+    descriptions.insert(0, Box::from("begin nftables transaction"));
+    messages.insert(0, begin);
+    descriptions.push(Box::from("commit nftables transaction"));
+    messages.push(end);
+
+    let mut socket = Socket::open(libc::NETLINK_NETFILTER)?;
+    let failed = report(&descriptions, socket.batch(&mut messages)?);
+    if failed != 0 {
+        bail!("nftables transaction aborted, {} messages failed", failed);
+    }
+    Ok(Box::from(""))
+}
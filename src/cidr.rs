@@ -0,0 +1,268 @@
+use crate::geoip::Cidr;
+use anyhow::{bail, Context as _, Ok, Result};
+
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+/// Parses `addr/prefix`, a bare address is taken as a host route.
+pub fn parse(s: &str) -> Result<Cidr> {
+    let (addr, prefix) = match s.split_once('/') {
+        Some((addr, prefix)) => (addr, Some(prefix)),
+        None => (s, None),
+    };
+    let ip: IpAddr = addr
+        .trim()
+        .parse()
+        .with_context(|| format!("Invalid CIDR: {}", s))?;
+    let (ip, bits) = match ip {
+        IpAddr::V4(ip) => (ip.octets().to_vec(), 32),
+        IpAddr::V6(ip) => (ip.octets().to_vec(), 128),
+    };
+    let prefix = match prefix {
+        Some(prefix) => prefix
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid CIDR: {}", s))?,
+        None => bits,
+    };
+    if prefix > bits {
+        bail!("Invalid CIDR: {}", s);
+    }
+    Ok(Cidr { ip, prefix })
+}
+
+/// Parses `start-last`, e.g. `1.0.1.0-1.0.3.255`.
+pub fn parse_range(s: &str) -> Result<Range> {
+    let Some((start, last)) = s.split_once('-') else {
+        bail!("Invalid range: {}", s);
+    };
+    let parse_addr = |addr: &str| {
+        addr.trim()
+            .parse()
+            .with_context(|| format!("Invalid range: {}", s))
+    };
+    Range::from_addrs(parse_addr(start)?, parse_addr(last)?)
+}
+
+/// An inclusive address range, addresses are stored right aligned in a `u128`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Range {
+    pub start: u128,
+    pub last: u128,
+    /// Address length in bytes, 4 or 16
+    pub len: usize,
+}
+
+impl Range {
+    pub fn from_cidr(cidr: &Cidr) -> Result<Self> {
+        let len = cidr.ip.len();
+        let bits = len as u32 * 8;
+        if len != 4 && len != 16 || cidr.prefix > bits {
+            bail!("Invalid CIDR");
+        }
+        let mut ip = [0_u8; 16];
+        ip[16 - len..].copy_from_slice(&cidr.ip);
+        let host = u128::MAX
+            .checked_shr(128 - (bits - cidr.prefix))
+            .unwrap_or(0);
+        let start = u128::from_be_bytes(ip) & !host;
+        Ok(Self {
+            start,
+            last: start | host,
+            len,
+        })
+    }
+
+    pub fn from_addrs(start: IpAddr, last: IpAddr) -> Result<Self> {
+        let (start, last, len) = match (start, last) {
+            (IpAddr::V4(start), IpAddr::V4(last)) => {
+                (u32::from(start).into(), u32::from(last).into(), 4)
+            }
+            (IpAddr::V6(start), IpAddr::V6(last)) => (u128::from(start), u128::from(last), 16),
+            _ => bail!("Mixed address families in range {}-{}", start, last),
+        };
+        if start > last {
+            bail!("Invalid range, start is greater than end");
+        }
+        Ok(Self { start, last, len })
+    }
+
+    /// The largest address of this family.
+    pub fn max_addr(&self) -> u128 {
+        u128::MAX >> (128 - self.len * 8)
+    }
+
+    pub fn addr(&self, value: u128) -> IpAddr {
+        if self.len == 4 {
+            IpAddr::V4(Ipv4Addr::from(value as u32))
+        } else {
+            IpAddr::V6(Ipv6Addr::from(value))
+        }
+    }
+
+    pub fn bytes(&self, value: u128) -> Vec<u8> {
+        value.to_be_bytes()[16 - self.len..].to_vec()
+    }
+
+    /// The prefix length, if this range is exactly one CIDR.
+    pub fn prefix(&self) -> Option<u32> {
+        let host = self.start ^ self.last;
+        if host & host.wrapping_add(1) != 0 || self.start & host != 0 {
+            return None;
+        }
+        Some(self.len as u32 * 8 - (128 - host.leading_zeros()))
+    }
+
+    /// Splits into the fewest CIDRs covering exactly this range.
+    pub fn to_cidrs(self) -> Vec<Cidr> {
+        let bits = self.len as u32 * 8;
+        let mut cidrs = Vec::new();
+        let mut start = self.start;
+        loop {
+            let span = self.last - start;
+            let fit = span.checked_add(1).map_or(128, |n| 127 - n.leading_zeros());
+            let size = fit.min(start.trailing_zeros()).min(bits);
+            cidrs.push(Cidr {
+                ip: self.bytes(start),
+                prefix: bits - size,
+            });
+            let block_last = start | u128::MAX.checked_shr(128 - size).unwrap_or(0);
+            if block_last == self.last {
+                return cidrs;
+            }
+            start = block_last + 1;
+        }
+    }
+}
+
+impl fmt::Display for Range {
+    /// Formats as `addr/prefix` when possible, `start-last` otherwise.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.prefix() {
+            Some(prefix) => write!(f, "{}/{}", self.addr(self.start), prefix),
+            None => write!(f, "{}-{}", self.addr(self.start), self.addr(self.last)),
+        }
+    }
+}
+
+/// Sorts CIDRs of one family and merges overlapping or adjacent ones.
+pub fn merge(elems: &[Cidr]) -> Result<Vec<Range>> {
+    let mut ranges = elems
+        .iter()
+        .map(Range::from_cidr)
+        .collect::<Result<Vec<_>>>()?;
+    ranges.sort_unstable();
+    let mut merged: Vec<Range> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(prev) if prev.len != range.len => bail!("Mixed address families"),
+            Some(prev) if prev.last == prev.max_addr() || range.start <= prev.last + 1 => {
+                prev.last = prev.last.max(range.last)
+            }
+            _ => merged.push(range),
+        }
+    }
+    Ok(merged)
+}
+
+/// Merges CIDRs of one family and splits the result back into the fewest CIDRs.
+pub fn aggregate(elems: &[Cidr]) -> Result<Vec<Cidr>> {
+    Ok(merge(elems)?
+        .into_iter()
+        .flat_map(Range::to_cidrs)
+        .collect())
+}
+
+/// The ranges of one family not covered by `merged`, which must come from [`merge`].
+pub fn complement(merged: &[Range], len: usize) -> Vec<Range> {
+    let max = u128::MAX >> (128 - len * 8);
+    let mut ranges = Vec::new();
+    let mut next = Some(0);
+    for range in merged {
+        if let Some(start) = next.filter(|&start| start < range.start) {
+            ranges.push(Range {
+                start,
+                last: range.start - 1,
+                len,
+            });
+        }
+        next = range.last.checked_add(1).filter(|&start| start <= max);
+    }
+    if let Some(start) = next {
+        ranges.push(Range {
+            start,
+            last: max,
+            len,
+        });
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{merge, parse, parse_range, Range};
+    use crate::generate::ToString as _;
+
+    fn split(range: &str) -> Vec<String> {
+        parse_range(range)
+            .unwrap()
+            .to_cidrs()
+            .iter()
+            .map(|cidr| cidr.to_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn range_splits_at_unaligned_boundaries() {
+        assert_eq!(split("1.0.1.0-1.0.3.255"), ["1.0.1.0/24", "1.0.2.0/23"]);
+        assert_eq!(
+            split("10.0.0.3 - 10.0.0.12"),
+            ["10.0.0.3/32", "10.0.0.4/30", "10.0.0.8/30", "10.0.0.12/32"]
+        );
+    }
+
+    #[test]
+    fn range_covers_everything_or_one_address() {
+        assert_eq!(split("0.0.0.0-255.255.255.255"), ["0.0.0.0/0"]);
+        assert_eq!(
+            split("::-ffff:ffff:ffff:ffff:ffff:ffff:ffff:ffff"),
+            ["::/0"]
+        );
+        assert_eq!(split("192.0.2.7-192.0.2.7"), ["192.0.2.7/32"]);
+        assert_eq!(
+            split("255.255.255.255-255.255.255.255"),
+            ["255.255.255.255/32"]
+        );
+    }
+
+    #[test]
+    fn range_splits_ipv6() {
+        assert_eq!(split("2001:db8::-2001:db8::ff"), ["2001:db8::/120"]);
+        assert_eq!(
+            split("2001:db8::1-2001:db8::2"),
+            ["2001:db8::1/128", "2001:db8::2/128"]
+        );
+    }
+
+    #[test]
+    fn range_rejects_reversed_and_mixed() {
+        assert!(parse_range("1.0.3.0-1.0.1.0").is_err());
+        assert!(parse_range("1.0.1.0-2001:db8::").is_err());
+        assert!(parse_range("1.0.1.0/24").is_err());
+        assert!(parse_range("1.0.1.0-").is_err());
+    }
+
+    #[test]
+    fn merge_joins_adjacent_and_overlapping() {
+        let cidrs =
+            ["1.0.2.0/23", "1.0.1.0/24", "1.0.2.0/24", "1.0.8.0/21"].map(|c| parse(c).unwrap());
+        let merged: Vec<String> = merge(&cidrs)
+            .unwrap()
+            .iter()
+            .map(Range::to_string)
+            .collect();
+        assert_eq!(merged, ["1.0.1.0-1.0.3.255", "1.0.8.0/21"]);
+    }
+}
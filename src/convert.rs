@@ -1,6 +1,13 @@
-use crate::{geoip::*, srs};
+use crate::{cidr, generate::ToString as _, geoip::*, mmdb, srs};
 use anyhow::{bail, Ok, Result};
 use prost::Message as _;
+use serde_json::{json, Value};
+
+use std::fmt::Write as _;
+
+pub const MRS_MAGIC: &[u8; 4] = b"MRS\x01";
+pub const MRS_BEHAVIOR_IPCIDR: u8 = 1;
+pub const MRS_IPCIDR_VERSION: u8 = 1;
 
 pub fn to_srs(cidr_pair: (Vec<Cidr>, Vec<Cidr>)) -> Result<Box<[u8]>> {
     use srs::{CIDRList, IPv4CIDR, IPv6CIDR};
@@ -60,3 +67,163 @@ pub fn to_ray(cidr_pair: (Vec<Cidr>, Vec<Cidr>), country_code: &str) -> Result<B
     geoip_list.encode(&mut buffer)?;
     Ok(buffer.into_boxed_slice())
 }
+
+/// A sing-box source rule-set, as taken by `sing-box rule-set compile`.
+/// Version 1 is understood by every sing-box release with rule-sets.
+pub fn to_srs_json(cidr_pair: (Vec<Cidr>, Vec<Cidr>)) -> Result<Box<[u8]>> {
+    let (ipv4_cidrs, ipv6_cidrs) = cidr_pair;
+    let ip_cidr = ipv4_cidrs
+        .iter()
+        .chain(&ipv6_cidrs)
+        .map(|cidr| cidr.to_string())
+        .collect::<Result<Vec<_>>>()?;
+    let rule_set = json!({
+        "version": 1,
+        "rules": [{ "ip_cidr": ip_cidr }],
+    });
+    let mut json = serde_json::to_vec_pretty(&rule_set)?;
+    json.push(b'\n');
+    Ok(json.into_boxed_slice())
+}
+
+pub fn to_mmdb(countries: Vec<(Box<str>, Vec<Cidr>)>) -> Result<Box<[u8]>> {
+    Ok(mmdb::write(&countries)?.into_boxed_slice())
+}
+
+/// An RFC 8805 geofeed, `prefix,country,region,city,postal`.
+pub fn to_geofeed(
+    countries: Vec<(Box<str>, Vec<Cidr>)>,
+    region: Option<&str>,
+    city: Option<&str>,
+) -> Result<Box<[u8]>> {
+    let mut csv = String::new();
+    for (code, cidrs) in countries {
+        for cidr in cidrs {
+            writeln!(
+                csv,
+                "{},{},{},{},",
+                cidr.to_string()?,
+                code.to_ascii_uppercase(),
+                region.unwrap_or_default(),
+                city.unwrap_or_default()
+            )?;
+        }
+    }
+    Ok(csv.into_bytes().into_boxed_slice())
+}
+
+/// A mihomo rule-provider with `behavior: ipcidr` and `format: yaml`.
+pub fn to_clash_yaml(cidr_pair: (Vec<Cidr>, Vec<Cidr>)) -> Result<Box<[u8]>> {
+    let (ipv4_cidrs, ipv6_cidrs) = cidr_pair;
+    let mut yaml = String::from("payload:\n");
+    for cidr in ipv4_cidrs.iter().chain(&ipv6_cidrs) {
+        writeln!(yaml, "  - '{}'", cidr.to_string()?)?;
+    }
+    Ok(yaml.into_bytes().into_boxed_slice())
+}
+
+/// A mihomo binary rule-set with `behavior: ipcidr`: zstd compressed magic,
+/// behavior, rule count, extra data, then the sorted ranges as 16 byte addresses,
+/// IPv4 ones mapped and first.
+pub fn to_mrs(cidr_pair: (Vec<Cidr>, Vec<Cidr>)) -> Result<Box<[u8]>> {
+    let (ipv4_cidrs, ipv6_cidrs) = cidr_pair;
+    let count = ipv4_cidrs.len() + ipv6_cidrs.len();
+    let ranges = [cidr::merge(&ipv4_cidrs)?, cidr::merge(&ipv6_cidrs)?].concat();
+    if ranges.is_empty() {
+        bail!("MRS can't hold an empty rule-set");
+    }
+    let mut data = Vec::new();
+    data.extend_from_slice(MRS_MAGIC);
+    data.push(MRS_BEHAVIOR_IPCIDR);
+    data.extend_from_slice(&(count as i64).to_be_bytes());
+    data.extend_from_slice(&0_i64.to_be_bytes());
+    data.push(MRS_IPCIDR_VERSION);
+    data.extend_from_slice(&(ranges.len() as i64).to_be_bytes());
+    for range in ranges {
+        for value in [range.start, range.last] {
+            let value = if range.len == 4 {
+                value | 0xffff_0000_0000
+            } else {
+                value
+            };
+            data.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+    Ok(zstd::encode_all(&*data, 0)?.into_boxed_slice())
+}
+
+fn family(cidr: &Cidr) -> &'static str {
+    if cidr.ip.len() == 4 {
+        "ipv4"
+    } else {
+        "ipv6"
+    }
+}
+
+pub fn to_text(cidr_pair: (Vec<Cidr>, Vec<Cidr>)) -> Result<Box<[u8]>> {
+    let (ipv4_cidrs, ipv6_cidrs) = cidr_pair;
+    let mut text = String::new();
+    for cidr in ipv4_cidrs.iter().chain(&ipv6_cidrs) {
+        writeln!(text, "{}", cidr.to_string()?)?;
+    }
+    Ok(text.into_bytes().into_boxed_slice())
+}
+
+/// Merges each family into maximal `start-last` ranges, one per line.
+pub fn to_ranges(cidr_pair: (Vec<Cidr>, Vec<Cidr>)) -> Result<Box<[u8]>> {
+    let (ipv4_cidrs, ipv6_cidrs) = cidr_pair;
+    let mut text = String::new();
+    for range in cidr::merge(&ipv4_cidrs)?
+        .into_iter()
+        .chain(cidr::merge(&ipv6_cidrs)?)
+    {
+        writeln!(
+            text,
+            "{}-{}",
+            range.addr(range.start),
+            range.addr(range.last)
+        )?;
+    }
+    Ok(text.into_bytes().into_boxed_slice())
+}
+
+pub fn to_csv(cidr_pair: (Vec<Cidr>, Vec<Cidr>), country_code: &str) -> Result<Box<[u8]>> {
+    let (ipv4_cidrs, ipv6_cidrs) = cidr_pair;
+    let country_code = country_code.to_ascii_uppercase();
+    let mut csv = String::from("cidr,family,code\n");
+    for cidr in ipv4_cidrs.iter().chain(&ipv6_cidrs) {
+        writeln!(
+            csv,
+            "{},{},{}",
+            cidr.to_string()?,
+            family(cidr),
+            country_code
+        )?;
+    }
+    Ok(csv.into_bytes().into_boxed_slice())
+}
+
+/// An array of `{"cidr", "family", "code"}` objects, `code` is null for sources
+/// without one, e.g. sing-box rule-sets.
+pub fn to_json(cidr_pair: (Vec<Cidr>, Vec<Cidr>), country_code: &str) -> Result<Box<[u8]>> {
+    let (ipv4_cidrs, ipv6_cidrs) = cidr_pair;
+    let code = if country_code.is_empty() {
+        Value::Null
+    } else {
+        Value::from(country_code.to_ascii_uppercase())
+    };
+    let entries = ipv4_cidrs
+        .iter()
+        .chain(&ipv6_cidrs)
+        .map(|cidr| {
+            Ok(json!({
+                "cidr": cidr.to_string()?,
+                "family": family(cidr),
+                "code": code,
+            }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut json = serde_json::to_vec_pretty(&entries)?;
+    json.push(b'\n');
+    Ok(json.into_boxed_slice())
+}
@@ -0,0 +1,196 @@
+use crate::geoip::Cidr;
+#[cfg(target_os = "linux")]
+use crate::netlink::{self, Message, Socket, NLM_F_DUMP, NLM_F_REQUEST};
+use anyhow::{bail, Context as _, Ok, Result};
+
+#[cfg(target_os = "linux")]
+use std::net::Ipv6Addr;
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr},
+};
+
+pub const AUTO: &str = "auto";
+
+const RTF_UP: u32 = 0x1;
+const RTF_GATEWAY: u32 = 0x2;
+
+#[cfg(target_os = "linux")]
+const RTM_GETROUTE: u16 = 26;
+#[cfg(target_os = "linux")]
+const RTMSG_LEN: usize = 12;
+#[cfg(target_os = "linux")]
+const RTA_OIF: u16 = 4;
+#[cfg(target_os = "linux")]
+const RTA_GATEWAY: u16 = 5;
+#[cfg(target_os = "linux")]
+const RTA_PRIORITY: u16 = 6;
+#[cfg(target_os = "linux")]
+const RTA_TABLE: u16 = 15;
+#[cfg(target_os = "linux")]
+const RTN_UNICAST: u8 = 1;
+#[cfg(target_os = "linux")]
+const RT_TABLE_MAIN: u32 = 254;
+
+struct DefaultRoute {
+    gateway: IpAddr,
+    dev: Box<str>,
+    metric: u32,
+}
+
+fn hex(field: &str) -> Result<u32> {
+    u32::from_str_radix(field, 16).with_context(|| format!("Invalid hex field: {}", field))
+}
+
+/// Default routes of the main table, read from `/proc/net/route`, lowest metric first.
+fn ipv4_default_routes() -> Result<Vec<DefaultRoute>> {
+    let content =
+        fs::read_to_string("/proc/net/route").context("Failed to read /proc/net/route")?;
+    let mut routes = Vec::new();
+    for line in content.lines().skip(1) {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 8 {
+            continue;
+        }
+        let (dev, destination, gateway, flags, metric, mask) = (
+            fields[0],
+            hex(fields[1])?,
+            hex(fields[2])?,
+            hex(fields[3])?,
+            fields[6].parse()?,
+            hex(fields[7])?,
+        );
+        if destination != 0 || mask != 0 || flags & (RTF_UP | RTF_GATEWAY) != RTF_UP | RTF_GATEWAY {
+            continue;
+        }
+        // The kernel prints the raw network order word in host order.
+        routes.push(DefaultRoute {
+            gateway: IpAddr::V4(Ipv4Addr::from(gateway.to_ne_bytes())),
+            dev: Box::from(dev),
+            metric,
+        });
+    }
+    routes.sort_by_key(|route| route.metric);
+    Ok(routes)
+}
+
+/// Default routes of the main table, dumped over rtnetlink, lowest metric first.
+/// `/proc/net/ipv6_route` can't be used as it lists every table.
+#[cfg(target_os = "linux")]
+fn ipv6_default_routes() -> Result<Vec<DefaultRoute>> {
+    let mut socket = Socket::open(libc::NETLINK_ROUTE)?;
+    let mut message = Message::new(RTM_GETROUTE, NLM_F_REQUEST | NLM_F_DUMP);
+    // struct rtmsg
+    message.header(&[libc::AF_INET6 as u8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    let mut routes = Vec::new();
+    for payload in socket.dump(message)? {
+        if payload.len() < RTMSG_LEN {
+            continue;
+        }
+        let (dst_len, mut table, ty) = (payload[1], u32::from(payload[4]), payload[7]);
+        if dst_len != 0 || ty != RTN_UNICAST {
+            continue;
+        }
+        let (mut gateway, mut oif, mut metric) = (None, 0, 0);
+        for (ty, data) in netlink::attrs(&payload, RTMSG_LEN) {
+            match ty {
+                RTA_TABLE => table = u32::from_ne_bytes(data.try_into()?),
+                RTA_GATEWAY => gateway = Some(Ipv6Addr::from(<[u8; 16]>::try_from(data)?)),
+                RTA_OIF => oif = u32::from_ne_bytes(data.try_into()?),
+                RTA_PRIORITY => metric = u32::from_ne_bytes(data.try_into()?),
+                _ => {}
+            }
+        }
+        // Multipath routes carry their gateways in `RTA_MULTIPATH`, those are skipped.
+        let Some(gateway) = gateway else {
+            continue;
+        };
+        if table != RT_TABLE_MAIN {
+            continue;
+        }
+        routes.push(DefaultRoute {
+            gateway: IpAddr::V6(gateway),
+            dev: ifname(oif)?,
+            metric,
+        });
+    }
+    routes.sort_by_key(|route| route.metric);
+    Ok(routes)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn ipv6_default_routes() -> Result<Vec<DefaultRoute>> {
+    bail!("Resolving the IPv6 default route is only supported on Linux")
+}
+
+#[cfg(target_os = "linux")]
+fn ifname(index: u32) -> Result<Box<str>> {
+    let mut name = [0; libc::IF_NAMESIZE];
+    if unsafe { libc::if_indextoname(index, name.as_mut_ptr()) }.is_null() {
+        bail!("Unknown interface index: {}", index);
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) };
+    Ok(Box::from(name.to_str()?))
+}
+
+/// Picks the preferred default route, the one on `dev` if a device is already known.
+fn pick<'a>(routes: &'a [DefaultRoute], dev: &str) -> Option<&'a DefaultRoute> {
+    routes
+        .iter()
+        .find(|route| &*route.dev == dev)
+        .or_else(|| routes.first())
+}
+
+/// Replaces `auto` gateways and device with the ones of the current default routes.
+///
+/// A family without CIDRs is left alone, so hosts without e.g. IPv6 connectivity
+/// don't need an IPv6 default route.
+pub fn resolve(
+    cidr_pair: &(Vec<Cidr>, Vec<Cidr>),
+    ipv4_gateway: &mut Box<str>,
+    ipv6_gateway: &mut Box<str>,
+    dev: &mut Box<str>,
+) -> Result<()> {
+    if &**ipv4_gateway != AUTO && &**ipv6_gateway != AUTO && &**dev != AUTO {
+        return Ok(());
+    }
+    let (ipv4_cidrs, ipv6_cidrs) = cidr_pair;
+    let ipv4_routes = if ipv4_cidrs.is_empty() {
+        Vec::new()
+    } else {
+        ipv4_default_routes()?
+    };
+    let ipv6_routes = if ipv6_cidrs.is_empty() {
+        Vec::new()
+    } else {
+        ipv6_default_routes()?
+    };
+
+    if &**dev == AUTO {
+        let Some(route) = ipv4_routes.first().or_else(|| ipv6_routes.first()) else {
+            bail!("No default route found to take the device from");
+        };
+        *dev = route.dev.clone();
+    }
+    for (gateway, routes, cidrs, family) in [
+        (ipv4_gateway, &ipv4_routes, ipv4_cidrs, "IPv4"),
+        (ipv6_gateway, &ipv6_routes, ipv6_cidrs, "IPv6"),
+    ] {
+        if &**gateway != AUTO || cidrs.is_empty() {
+            continue;
+        }
+        let Some(route) = pick(routes, dev) else {
+            bail!("No {} default route found", family);
+        };
+        if route.dev != *dev {
+            bail!(
+                "{} default route is on {}, not on {}",
+                family,
+                route.dev,
+                dev
+            );
+        }
+        *gateway = route.gateway.to_string().into_boxed_str();
+    }
+    Ok(())
+}
@@ -1,4 +1,4 @@
-use crate::geoip::Cidr;
+use crate::{cidr, geoip::Cidr};
 use anyhow::{bail, Context as _, Ok, Result};
 
 use std::{
@@ -6,7 +6,13 @@ use std::{
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
 };
 
-trait ToString {
+/// Protocol number marking the routes and rules tsumugi installs, so that reloads
+/// leave everything else alone.
+pub const PROTOCOL: u8 = 200;
+/// What `ip` shows for [`PROTOCOL`] once it is named in `/etc/iproute2/rt_protos`.
+pub const PROTOCOL_NAME: &str = "tsumugi";
+
+pub(crate) trait ToString {
     fn to_string(&self) -> Result<String>;
 }
 impl ToString for Cidr {
@@ -75,19 +81,21 @@ pub fn iproute2rule(
     for elem in ipv4_cidrs {
         writeln!(
             script,
-            "ip rule {} to {} lookup {}",
+            "ip rule {} to {} lookup {} protocol {}",
             action,
             elem.to_string()?,
-            table
+            table,
+            PROTOCOL
         )?;
     }
     for elem in ipv6_cidrs {
         writeln!(
             script,
-            "ip -6 rule {} to {} lookup {}",
+            "ip -6 rule {} to {} lookup {} protocol {}",
             action,
             elem.to_string()?,
-            table
+            table,
+            PROTOCOL
         )?;
     }
     Ok(script.into_boxed_str())
@@ -107,24 +115,537 @@ pub fn iproute2route(
     for elem in ipv4_cidrs {
         writeln!(
             script,
-            "ip route {} table {} {} via {} dev {}",
+            "ip route {} table {} {} via {} dev {} proto {}",
             action,
             table,
             elem.to_string()?,
             ipv4_gateway,
-            dev
+            dev,
+            PROTOCOL
         )?;
     }
     for elem in ipv6_cidrs {
         writeln!(
             script,
-            "ip -6 route {} table {} {} via {} dev {}",
+            "ip -6 route {} table {} {} via {} dev {} proto {}",
             action,
             table,
             elem.to_string()?,
             ipv6_gateway,
-            dev
+            dev,
+            PROTOCOL
+        )?;
+    }
+    Ok(script.into_boxed_str())
+}
+
+/// A shell script setting up policy routing in one go: the `rt_tables` entry,
+/// the routes in that table, and either a rule per CIDR or, with `fwmark`
+/// (mark, nftables table, IPv4 set, IPv6 set), nftables sets marking the
+/// traffic plus a single fwmark rule. Routes and rules carrying [`PROTOCOL`]
+/// in that table are flushed first, so running it again replaces the last run.
+pub fn policy_routing(
+    cidr_pair: (Vec<Cidr>, Vec<Cidr>),
+    table: &str,
+    table_id: u32,
+    ipv4_gateway: &str,
+    ipv6_gateway: &str,
+    dev: &str,
+    fwmark: Option<(&str, &str, &str, &str)>,
+) -> Result<Box<str>> {
+    let mut script = String::new();
+    writeln!(script, "#!/bin/sh\nset -e\n")?;
+    writeln!(
+        script,
+        "\
+mkdir -p /etc/iproute2
+if ! grep -qE '^[[:space:]]*{table_id}[[:space:]]+{table}([[:space:]]|$)' /etc/iproute2/rt_tables 2>/dev/null; then
+    if grep -qE '^[[:space:]]*({table_id}[[:space:]]|[0-9]+[[:space:]]+{table}([[:space:]]|$))' /etc/iproute2/rt_tables 2>/dev/null; then
+        echo 'Table id {table_id} or name {table} is taken in /etc/iproute2/rt_tables' >&2
+        exit 1
+    fi
+    echo '{table_id} {table}' >> /etc/iproute2/rt_tables
+fi
+"
+    )?;
+    // The IPv4 table only exists once a route was added to it, the flush fails before.
+    for ip in ["ip", "ip -6"] {
+        writeln!(script, "{ip} rule flush table {table} protocol {PROTOCOL}")?;
+        writeln!(
+            script,
+            "{ip} route flush table {table} proto {PROTOCOL} 2>/dev/null || true"
+        )?;
+    }
+    if let Some((mark, nf_table, ipv4set, ipv6set)) = fwmark {
+        writeln!(script)?;
+        writeln!(script, "nft -f - <<'NFT'")?;
+        writeln!(script, "add table inet {nf_table}")?;
+        for (set, ty) in [(ipv4set, "ipv4_addr"), (ipv6set, "ipv6_addr")] {
+            writeln!(
+                script,
+                "add set inet {nf_table} {set} {{ type {ty}; flags interval; auto-merge; }}"
+            )?;
+        }
+        for (chain, ty) in [("prerouting", "filter"), ("output", "route")] {
+            writeln!(
+                script,
+                "add chain inet {nf_table} {chain} {{ type {ty} hook {chain} priority mangle; }}"
+            )?;
+            writeln!(script, "flush chain inet {nf_table} {chain}")?;
+            for (family, set) in [("ip", ipv4set), ("ip6", ipv6set)] {
+                writeln!(
+                    script,
+                    "add rule inet {nf_table} {chain} {family} daddr @{set} meta mark set {mark}"
+                )?;
+            }
+        }
+        script.push_str(&nftables(cidr_pair.clone(), nf_table, ipv4set, ipv6set)?);
+        writeln!(script, "NFT")?;
+    }
+    // One `ip -batch` per family instead of a fork per CIDR.
+    let (ipv4_cidrs, ipv6_cidrs) = cidr_pair;
+    for (ip, elems, gateway) in [
+        ("ip", ipv4_cidrs, ipv4_gateway),
+        ("ip -6", ipv6_cidrs, ipv6_gateway),
+    ] {
+        if elems.is_empty() && fwmark.is_none() {
+            continue;
+        }
+        writeln!(script, "\n{ip} -batch - <<'IP'")?;
+        for elem in &elems {
+            writeln!(
+                script,
+                "route add table {table} {} via {gateway} dev {dev} proto {PROTOCOL}",
+                elem.to_string()?
+            )?;
+        }
+        match fwmark {
+            Some((mark, ..)) => writeln!(
+                script,
+                "rule add fwmark {mark} lookup {table} protocol {PROTOCOL}"
+            )?,
+            None => {
+                for elem in &elems {
+                    writeln!(
+                        script,
+                        "rule add to {} lookup {table} protocol {PROTOCOL}",
+                        elem.to_string()?
+                    )?;
+                }
+            }
+        }
+        writeln!(script, "IP")?;
+    }
+    Ok(script.into_boxed_str())
+}
+
+/// A `.network` drop-in, to be placed in e.g.
+/// `/etc/systemd/network/<name>.network.d/`, so networkd installs the routes itself.
+pub fn networkd(
+    cidr_pair: (Vec<Cidr>, Vec<Cidr>),
+    table: &str,
+    ipv4_gateway: &str,
+    ipv6_gateway: &str,
+    metric: Option<u32>,
+    rules: bool,
+    priority: Option<u32>,
+) -> Result<Box<str>> {
+    let (ipv4_cidrs, ipv6_cidrs) = cidr_pair;
+    let mut config = String::new();
+    for (elems, gateway) in [(&ipv4_cidrs, ipv4_gateway), (&ipv6_cidrs, ipv6_gateway)] {
+        for elem in elems {
+            writeln!(config, "[Route]")?;
+            writeln!(config, "Destination={}", elem.to_string()?)?;
+            writeln!(config, "Gateway={}", gateway)?;
+            writeln!(config, "Table={}", table)?;
+            if let Some(metric) = metric {
+                writeln!(config, "Metric={}", metric)?;
+            }
+            writeln!(config)?;
+        }
+    }
+    if rules {
+        for elem in ipv4_cidrs.iter().chain(&ipv6_cidrs) {
+            writeln!(config, "[RoutingPolicyRule]")?;
+            writeln!(config, "To={}", elem.to_string()?)?;
+            writeln!(config, "Table={}", table)?;
+            if let Some(priority) = priority {
+                writeln!(config, "Priority={}", priority)?;
+            }
+            writeln!(config)?;
+        }
+    }
+    Ok(config.into_boxed_str())
+}
+
+/// `define` prefix sets for use in BIRD 2 filters, e.g. `if net ~ CN_V4 then accept;`,
+/// and optionally a `protocol static` per family originating the routes.
+pub fn bird(
+    cidr_pair: (Vec<Cidr>, Vec<Cidr>),
+    ipv4_name: &str,
+    ipv6_name: &str,
+    protocol: &str,
+    ipv4_nexthop: Option<&str>,
+    ipv6_nexthop: Option<&str>,
+) -> Result<Box<str>> {
+    let (ipv4_cidrs, ipv6_cidrs) = cidr_pair;
+    let mut config = String::new();
+    for (name, elems) in [(ipv4_name, &ipv4_cidrs), (ipv6_name, &ipv6_cidrs)] {
+        writeln!(config, "define {} = [", name)?;
+        let mut elem_iter = elems.iter().peekable();
+        while let Some(elem) = elem_iter.next() {
+            let separator = if elem_iter.peek().is_some() { "," } else { "" };
+            writeln!(config, "    {}{}", elem.to_string()?, separator)?;
+        }
+        writeln!(config, "];\n")?;
+    }
+    for (family, elems, nexthop) in [
+        ("ipv4", &ipv4_cidrs, ipv4_nexthop),
+        ("ipv6", &ipv6_cidrs, ipv6_nexthop),
+    ] {
+        let Some(nexthop) = nexthop else {
+            continue;
+        };
+        writeln!(config, "protocol static {}{} {{", protocol, &family[3..])?;
+        writeln!(config, "    {};", family)?;
+        for elem in elems {
+            writeln!(config, "    route {} {};", elem.to_string()?, nexthop)?;
+        }
+        writeln!(config, "}}\n")?;
+    }
+    Ok(config.into_boxed_str())
+}
+
+/// Configuration lines for `vtysh -f`: a prefix list per family, numbered
+/// like FRR does itself (5, 10, ...), and static routes if a next hop is given.
+pub fn frr(
+    cidr_pair: (Vec<Cidr>, Vec<Cidr>),
+    name: &str,
+    ipv4_nexthop: Option<&str>,
+    ipv6_nexthop: Option<&str>,
+    table: Option<u32>,
+) -> Result<Box<str>> {
+    let (ipv4_cidrs, ipv6_cidrs) = cidr_pair;
+    let mut config = String::new();
+    for (family, elems) in [("ip", &ipv4_cidrs), ("ipv6", &ipv6_cidrs)] {
+        for (i, elem) in elems.iter().enumerate() {
+            writeln!(
+                config,
+                "{} prefix-list {} seq {} permit {}",
+                family,
+                name,
+                (i + 1) * 5,
+                elem.to_string()?
+            )?;
+        }
+    }
+    let table = match table {
+        Some(table) => format!(" table {}", table),
+        None => String::new(),
+    };
+    for (family, elems, nexthop) in [
+        ("ip", &ipv4_cidrs, ipv4_nexthop),
+        ("ipv6", &ipv6_cidrs, ipv6_nexthop),
+    ] {
+        let Some(nexthop) = nexthop else {
+            continue;
+        };
+        for elem in elems {
+            writeln!(
+                config,
+                "{} route {} {}{}",
+                family,
+                elem.to_string()?,
+                nexthop,
+                table
+            )?;
+        }
+    }
+    Ok(config.into_boxed_str())
+}
+
+/// A RouterOS script replacing the entries of an address list, and the routes
+/// carrying `comment` if gateways are given, e.g. for `/import file-name=cn.rsc`.
+pub fn routeros(
+    cidr_pair: (Vec<Cidr>, Vec<Cidr>),
+    list: &str,
+    comment: &str,
+    ipv4_gateway: Option<&str>,
+    ipv6_gateway: Option<&str>,
+    table: &str,
+) -> Result<Box<str>> {
+    let (ipv4_cidrs, ipv6_cidrs) = cidr_pair;
+    let mut script = String::new();
+    for (menu, elems) in [("/ip", &ipv4_cidrs), ("/ipv6", &ipv6_cidrs)] {
+        writeln!(script, "{} firewall address-list", menu)?;
+        writeln!(script, "remove [find list=\"{}\"]", list)?;
+        for elem in elems {
+            writeln!(
+                script,
+                "add list=\"{}\" address={} comment=\"{}\"",
+                list,
+                elem.to_string()?,
+                comment
+            )?;
+        }
+    }
+    for (menu, elems, gateway) in [
+        ("/ip", &ipv4_cidrs, ipv4_gateway),
+        ("/ipv6", &ipv6_cidrs, ipv6_gateway),
+    ] {
+        let Some(gateway) = gateway else {
+            continue;
+        };
+        writeln!(script, "{} route", menu)?;
+        writeln!(script, "remove [find comment=\"{}\"]", comment)?;
+        for elem in elems {
+            writeln!(
+                script,
+                "add dst-address={} gateway={} routing-table=\"{}\" comment=\"{}\"",
+                elem.to_string()?,
+                gateway,
+                table,
+                comment
+            )?;
+        }
+    }
+    Ok(script.into_boxed_str())
+}
+
+/// `config ipset` sections for `/etc/config/firewall`, so fw4 creates the sets and
+/// keeps them across reloads. With `loadfile`, fw4 reads the entries from
+/// `<loadfile>/<set>`, which `entries` generates for one set.
+pub fn openwrt(
+    cidr_pair: (Vec<Cidr>, Vec<Cidr>),
+    ipv4set: &str,
+    ipv6set: &str,
+    loadfile: Option<&str>,
+    entries: Option<&str>,
+) -> Result<Box<str>> {
+    let (ipv4_cidrs, ipv6_cidrs) = cidr_pair;
+    let mut config = String::new();
+    let sets = [
+        (ipv4set, "ipv4", &ipv4_cidrs),
+        (ipv6set, "ipv6", &ipv6_cidrs),
+    ];
+    if let Some(entries) = entries {
+        let Some((_, _, elems)) = sets.iter().find(|(set, _, _)| *set == entries) else {
+            bail!("No such set: {}", entries);
+        };
+        for elem in elems.iter() {
+            writeln!(config, "{}", elem.to_string()?)?;
+        }
+        return Ok(config.into_boxed_str());
+    }
+    for (set, family, elems) in sets {
+        writeln!(config, "config ipset")?;
+        writeln!(config, "\toption name '{}'", set)?;
+        writeln!(config, "\toption family '{}'", family)?;
+        writeln!(config, "\tlist match 'dest_net'")?;
+        if let Some(loadfile) = loadfile {
+            writeln!(
+                config,
+                "\toption loadfile '{}/{}'",
+                loadfile.trim_end_matches('/'),
+                set
+            )?;
+        } else {
+            for elem in elems {
+                writeln!(config, "\tlist entry '{}'", elem.to_string()?)?;
+            }
+        }
+        writeln!(config)?;
+    }
+    Ok(config.into_boxed_str())
+}
+
+/// A firewalld ipset of one family, for `/etc/firewalld/ipsets/<name>.xml`.
+/// The name of the ipset is the file name, so the IPv4 and IPv6 ones are
+/// generated separately.
+pub fn firewalld(cidr_pair: (Vec<Cidr>, Vec<Cidr>), ipv6: bool) -> Result<Box<str>> {
+    let (ipv4_cidrs, ipv6_cidrs) = cidr_pair;
+    let (family, elems) = if ipv6 {
+        ("inet6", ipv6_cidrs)
+    } else {
+        ("inet", ipv4_cidrs)
+    };
+    let mut xml = String::new();
+    writeln!(xml, "<?xml version=\"1.0\" encoding=\"utf-8\"?>")?;
+    writeln!(xml, "<ipset type=\"hash:net\">")?;
+    writeln!(xml, "  <option name=\"family\" value=\"{}\"/>", family)?;
+    // ipset refuses more elements than maxelem, which defaults to 65536.
+    if elems.len() > 65536 {
+        writeln!(
+            xml,
+            "  <option name=\"maxelem\" value=\"{}\"/>",
+            elems.len()
         )?;
     }
+    for elem in elems {
+        writeln!(xml, "  <entry>{}</entry>", elem.to_string()?)?;
+    }
+    writeln!(xml, "</ipset>")?;
+    Ok(xml.into_boxed_str())
+}
+
+/// A `pf.conf` table definition, or the plain list a URL table alias fetches.
+pub fn pf(cidr_pair: (Vec<Cidr>, Vec<Cidr>), table: &str, alias: bool) -> Result<Box<str>> {
+    let (ipv4_cidrs, ipv6_cidrs) = cidr_pair;
+    let mut config = String::new();
+    if alias {
+        for elem in ipv4_cidrs.iter().chain(&ipv6_cidrs) {
+            writeln!(config, "{}", elem.to_string()?)?;
+        }
+        return Ok(config.into_boxed_str());
+    }
+    write!(config, "table <{}> persist {{", table)?;
+    for elem in ipv4_cidrs.iter().chain(&ipv6_cidrs) {
+        write!(config, " \\\n    {}", elem.to_string()?)?;
+    }
+    writeln!(config, " }}")?;
+    Ok(config.into_boxed_str())
+}
+
+/// Ranges a tunnel should never capture: this-network, private, CGNAT, loopback,
+/// link-local, multicast and reserved ones.
+const PRIVATE_RANGES: [&str; 12] = [
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "224.0.0.0/3",
+    "::/8",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// `AllowedIPs` of a peer routing everything except the CIDRs and `exclude`,
+/// aggregated to the fewest CIDRs, or a whole `[Peer]` section with `public_key`.
+pub fn wireguard(
+    cidr_pair: (Vec<Cidr>, Vec<Cidr>),
+    exclude: &[Cidr],
+    keep_private: bool,
+    public_key: Option<&str>,
+    endpoint: Option<&str>,
+) -> Result<Box<str>> {
+    let (mut ipv4_cidrs, mut ipv6_cidrs) = cidr_pair;
+    let private = if keep_private {
+        Vec::new()
+    } else {
+        PRIVATE_RANGES
+            .iter()
+            .map(|range| cidr::parse(range))
+            .collect::<Result<Vec<_>>>()?
+    };
+    for elem in exclude.iter().chain(&private) {
+        match elem.ip.len() {
+            4 => ipv4_cidrs.push(elem.clone()),
+            _ => ipv6_cidrs.push(elem.clone()),
+        }
+    }
+    let mut allowed_ips = Vec::new();
+    for (elems, len) in [(&ipv4_cidrs, 4), (&ipv6_cidrs, 16)] {
+        for range in cidr::complement(&cidr::merge(elems)?, len) {
+            for elem in range.to_cidrs() {
+                allowed_ips.push(elem.to_string()?);
+            }
+        }
+    }
+
+    let mut config = String::new();
+    if let Some(public_key) = public_key {
+        writeln!(config, "[Peer]")?;
+        writeln!(config, "PublicKey = {}", public_key)?;
+        if let Some(endpoint) = endpoint {
+            writeln!(config, "Endpoint = {}", endpoint)?;
+        }
+    }
+    writeln!(config, "AllowedIPs = {}", allowed_ips.join(", "))?;
+    Ok(config.into_boxed_str())
+}
+
+/// `route` and `route-ipv6` directives for an OpenVPN configuration, or with
+/// `push` the same directives pushed to the clients by a server.
+pub fn openvpn(
+    cidr_pair: (Vec<Cidr>, Vec<Cidr>),
+    ipv4_gateway: &str,
+    ipv6_gateway: Option<&str>,
+    push: bool,
+) -> Result<Box<str>> {
+    let (ipv4_cidrs, ipv6_cidrs) = cidr_pair;
+    let mut directives = Vec::new();
+    for elem in ipv4_cidrs {
+        let netmask = Ipv4Addr::from(u32::MAX.checked_shl(32 - elem.prefix).unwrap_or(0));
+        let network = Ipv4Addr::from(
+            <[u8; 4]>::try_from(elem.ip.as_slice()).context("Failed to convert to Ipv4Addr")?,
+        );
+        directives.push(format!("route {} {} {}", network, netmask, ipv4_gateway));
+    }
+    for elem in ipv6_cidrs {
+        let mut directive = format!("route-ipv6 {}", elem.to_string()?);
+        if let Some(ipv6_gateway) = ipv6_gateway {
+            write!(directive, " {}", ipv6_gateway)?;
+        }
+        directives.push(directive);
+    }
+
+    let mut config = String::new();
+    for directive in directives {
+        if push {
+            writeln!(config, "push \"{}\"", directive)?;
+        } else {
+            writeln!(config, "{}", directive)?;
+        }
+    }
+    Ok(config.into_boxed_str())
+}
+
+/// `tc filter` commands for `tc -batch`, sending traffic to or from the CIDRs to
+/// `classid`. A priority belongs to one protocol, so IPv6 uses `prio + 1`.
+#[allow(clippy::too_many_arguments)]
+pub fn tc(
+    cidr_pair: (Vec<Cidr>, Vec<Cidr>),
+    dev: &str,
+    parent: &str,
+    classid: &str,
+    prio: u32,
+    src: bool,
+    u32: bool,
+) -> Result<Box<str>> {
+    let (ipv4_cidrs, ipv6_cidrs) = cidr_pair;
+    let mut script = String::new();
+    let direction = if src { "src" } else { "dst" };
+    for (protocol, prio, elems, u32_match) in [
+        ("ip", prio, ipv4_cidrs, "ip"),
+        ("ipv6", prio + 1, ipv6_cidrs, "ip6"),
+    ] {
+        for elem in elems {
+            let selector = if u32 {
+                format!(
+                    "u32 match {} {} {} flowid {}",
+                    u32_match,
+                    direction,
+                    elem.to_string()?,
+                    classid
+                )
+            } else {
+                format!(
+                    "flower {}_ip {} classid {}",
+                    direction,
+                    elem.to_string()?,
+                    classid
+                )
+            };
+            writeln!(
+                script,
+                "filter add dev {} parent {} protocol {} prio {} {}",
+                dev, parent, protocol, prio, selector
+            )?;
+        }
+    }
     Ok(script.into_boxed_str())
 }
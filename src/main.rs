@@ -1,7 +1,15 @@
 mod app;
+#[cfg(target_os = "linux")]
+mod apply;
+mod cidr;
 mod convert;
+mod gateway;
 mod generate;
+mod mmdb;
+#[cfg(target_os = "linux")]
+mod netlink;
 mod read;
+mod reconcile;
 mod srs {
     include!(concat!(env!("OUT_DIR"), "/libsrs.rs"));
 }
@@ -26,6 +34,17 @@ fn main() -> Result<()> {
         args.source_group.url.unwrap_or_default(),
         args.output.unwrap_or_default(),
     );
+    let read_options = read::Options {
+        registered_country: args.registered_country,
+        locations: args.locations,
+        statuses: args.status,
+        region: args.select_region,
+        asns: args
+            .asn
+            .iter()
+            .map(|asn| read::parse_asn(asn))
+            .collect::<Result<_>>()?,
+    };
     let (mut nftables, mut nf_table, mut nf_ipv4set, mut nf_ipv6set) =
         (false, Box::from(""), Box::from(""), Box::from(""));
     let (mut iproute2_rule, mut iproute2_route) = (false, false);
@@ -37,46 +56,55 @@ fn main() -> Result<()> {
         Box::from(""),
         Box::from(""),
     );
+    let (mut apply, mut dry_run, mut flush) = (false, false, false);
+    let mut current = Vec::new();
+    let mut generator = None;
     let mut systemd = false;
     let (mut to_srs, mut to_ray) = (false, false);
+    let mut converter = None;
     match args.command {
         Some(Commands::Generate {
             generate_command: c,
-        }) => match c {
-            GenerateCommands::Nftables {
-                table,
-                ipv4set,
-                ipv6set,
-            } => {
-                nftables = true;
-                nf_table = table;
-                nf_ipv4set = ipv4set;
-                nf_ipv6set = ipv6set;
-            }
-            GenerateCommands::Iproute2 {
-                iproute2_command: c,
-            } => match c {
-                Iproute2Commands::Rule { delete: d, table } => {
-                    delete = d;
-                    iproute2_rule = true;
-                    ru_table = table;
-                }
-                Iproute2Commands::Route {
-                    delete: d,
+            current: cur,
+        }) => {
+            current = cur;
+            match c {
+                GenerateCommands::Nftables {
                     table,
-                    ipv4_gateway,
-                    ipv6_gateway,
-                    dev,
+                    ipv4set,
+                    ipv6set,
                 } => {
-                    delete = d;
-                    iproute2_route = true;
-                    r_table = table;
-                    r_ipv4_gateway = ipv4_gateway;
-                    r_ipv6_gateway = ipv6_gateway;
-                    r_dev = dev;
+                    nftables = true;
+                    nf_table = table;
+                    nf_ipv4set = ipv4set;
+                    nf_ipv6set = ipv6set;
                 }
-            },
-        },
+                GenerateCommands::Iproute2 {
+                    iproute2_command: c,
+                } => match c {
+                    Iproute2Commands::Rule { delete: d, table } => {
+                        delete = d;
+                        iproute2_rule = true;
+                        ru_table = table;
+                    }
+                    Iproute2Commands::Route {
+                        delete: d,
+                        table,
+                        ipv4_gateway,
+                        ipv6_gateway,
+                        dev,
+                    } => {
+                        delete = d;
+                        iproute2_route = true;
+                        r_table = table;
+                        r_ipv4_gateway = ipv4_gateway;
+                        r_ipv6_gateway = ipv6_gateway;
+                        r_dev = dev;
+                    }
+                },
+                c => generator = Some(c),
+            }
+        }
         Some(Commands::Convert {
             convert_command: c,
             output: o,
@@ -85,6 +113,51 @@ fn main() -> Result<()> {
             match c {
                 ConvertCommands::Srs {} => to_srs = true,
                 ConvertCommands::Ray {} => to_ray = true,
+                c => converter = Some(c),
+            }
+        }
+        Some(Commands::Apply {
+            apply_command: c,
+            dry_run: d,
+        }) => {
+            apply = true;
+            dry_run = d;
+            match c {
+                ApplyCommands::Nftables {
+                    flush: f,
+                    table,
+                    ipv4set,
+                    ipv6set,
+                } => {
+                    flush = f;
+                    nftables = true;
+                    nf_table = table;
+                    nf_ipv4set = ipv4set;
+                    nf_ipv6set = ipv6set;
+                }
+                ApplyCommands::Iproute2 {
+                    iproute2_command: c,
+                } => match c {
+                    Iproute2Commands::Rule { delete: d, table } => {
+                        delete = d;
+                        iproute2_rule = true;
+                        ru_table = table;
+                    }
+                    Iproute2Commands::Route {
+                        delete: d,
+                        table,
+                        ipv4_gateway,
+                        ipv6_gateway,
+                        dev,
+                    } => {
+                        delete = d;
+                        iproute2_route = true;
+                        r_table = table;
+                        r_ipv4_gateway = ipv4_gateway;
+                        r_ipv6_gateway = ipv6_gateway;
+                        r_dev = dev;
+                    }
+                },
             }
         }
         Some(Commands::Systemd {
@@ -126,12 +199,34 @@ fn main() -> Result<()> {
                         r_dev = dev;
                     }
                 },
+                _ => {
+                    cmd.subcommand_value_name("systemd")
+                        .error(
+                            clap::error::ErrorKind::InvalidSubcommand,
+                            "This generator has no systemd service unit",
+                        )
+                        .exit();
+                }
             }
         }
         None => {
             unreachable!()
         }
     }
+    if !current.is_empty() && (delete || generator.is_some()) {
+        cmd.error(
+            clap::error::ErrorKind::ArgumentConflict,
+            "--current only applies to the nftables and iproute2 generators without --delete",
+        )
+        .exit();
+    }
+    if !flush && url.is_empty() && args.source_group.file.is_none() {
+        cmd.error(
+            clap::error::ErrorKind::MissingRequiredArgument,
+            "the following required arguments were not provided:\n  \x1b[32m<--file <FILE>|--url <URL>>\x1b[0m",
+        )
+        .exit();
+    }
     let print = output.is_empty();
     let s: Box<str>;
     let buffer: Box<[u8]>;
@@ -146,18 +241,26 @@ fn main() -> Result<()> {
                 .exit();
         }
         s = if nftables {
-            systemd::generate_nftables(&url, &code, &nf_table, &nf_ipv4set, &nf_ipv6set)?
+            systemd::generate_nftables(
+                &url,
+                &code,
+                &read_options,
+                &nf_table,
+                &nf_ipv4set,
+                &nf_ipv6set,
+            )?
         } else if iproute2_route {
             systemd::generate_iproute2_route(
                 &url,
                 &code,
+                &read_options,
                 &r_table,
                 &r_ipv4_gateway,
                 &r_ipv6_gateway,
                 &r_dev,
             )?
         } else if iproute2_rule {
-            systemd::generate_iproute2_rule(&url, &code, &ru_table)?
+            systemd::generate_iproute2_rule(&url, &code, &read_options, &ru_table)?
         } else {
             unreachable!()
         };
@@ -166,17 +269,84 @@ fn main() -> Result<()> {
         } else {
             vec![].into()
         }
+    } else if let Some(c) = converter.take_if(|c| {
+        code.is_empty()
+            && matches!(
+                c,
+                ConvertCommands::Mmdb {} | ConvertCommands::Geofeed { .. }
+            )
+    }) {
+        let countries = if !url.is_empty() {
+            read::fetch_countries(&url)?
+        } else if let Some(file) = &args.source_group.file {
+            read::countries_from_file(file.parse()?)?
+        } else {
+            unreachable!()
+        };
+        s = Box::from("");
+        buffer = convert_countries(countries, c)?;
     } else {
-        let cidr_pair = if !url.is_empty() {
-            read::fetch(&code, &url)?
+        let cidr_pair = if flush {
+            (Vec::new(), Vec::new())
+        } else if !url.is_empty() {
+            read::fetch(&code, &url, &read_options)?
         } else if let Some(file) = &args.source_group.file {
-            read::from_file(&code, file.parse()?)?
+            read::from_file(&code, file.parse()?, &read_options)?
         } else {
             unreachable!()
         };
+        if iproute2_route {
+            gateway::resolve(
+                &cidr_pair,
+                &mut r_ipv4_gateway,
+                &mut r_ipv6_gateway,
+                &mut r_dev,
+            )?;
+        }
 
-        if nftables || iproute2_route || iproute2_rule {
-            s = if nftables {
+        if apply {
+            s = apply_rules(
+                cidr_pair,
+                dry_run,
+                nftables,
+                (&nf_table, &nf_ipv4set, &nf_ipv6set),
+                iproute2_rule,
+                delete,
+                &ru_table,
+                &r_table,
+                &r_ipv4_gateway,
+                &r_ipv6_gateway,
+                &r_dev,
+            )?;
+            buffer = if !print {
+                s.as_bytes().into()
+            } else {
+                vec![].into()
+            }
+        } else if let Some(c) = generator {
+            s = generate_with(cidr_pair, c)?;
+            buffer = if !print {
+                s.as_bytes().into()
+            } else {
+                vec![].into()
+            }
+        } else if nftables || iproute2_route || iproute2_rule {
+            s = if !current.is_empty() {
+                if nftables {
+                    reconcile::nftables(cidr_pair, &current, &nf_table, &nf_ipv4set, &nf_ipv6set)?
+                } else if iproute2_rule {
+                    reconcile::iproute2rule(cidr_pair, &current, &ru_table)?
+                } else {
+                    reconcile::iproute2route(
+                        cidr_pair,
+                        &current,
+                        &r_table,
+                        &r_ipv4_gateway,
+                        &r_ipv6_gateway,
+                        &r_dev,
+                    )?
+                }
+            } else if nftables {
                 generate::nftables(cidr_pair, &nf_table, &nf_ipv4set, &nf_ipv6set)?
             } else if iproute2_rule {
                 generate::iproute2rule(cidr_pair, delete, &ru_table)?
@@ -201,6 +371,8 @@ fn main() -> Result<()> {
                 convert::to_srs(cidr_pair)?
             } else if to_ray {
                 convert::to_ray(cidr_pair, &code)?
+            } else if let Some(c) = converter {
+                convert_with(cidr_pair, &code, c)?
             } else {
                 unreachable!()
             };
@@ -215,3 +387,237 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+fn generate_with(
+    cidr_pair: (Vec<geoip::Cidr>, Vec<geoip::Cidr>),
+    command: GenerateCommands,
+) -> Result<Box<str>> {
+    match command {
+        GenerateCommands::PolicyRouting {
+            table,
+            table_id,
+            mut ipv4_gateway,
+            mut ipv6_gateway,
+            mut dev,
+            fwmark,
+            nft_table,
+            ipv4set,
+            ipv6set,
+        } => {
+            gateway::resolve(&cidr_pair, &mut ipv4_gateway, &mut ipv6_gateway, &mut dev)?;
+            generate::policy_routing(
+                cidr_pair,
+                &table,
+                table_id,
+                &ipv4_gateway,
+                &ipv6_gateway,
+                &dev,
+                fwmark
+                    .as_deref()
+                    .map(|mark| (mark, &*nft_table, &*ipv4set, &*ipv6set)),
+            )
+        }
+        GenerateCommands::Networkd {
+            table,
+            ipv4_gateway,
+            ipv6_gateway,
+            metric,
+            rules,
+            priority,
+        } => generate::networkd(
+            cidr_pair,
+            &table,
+            &ipv4_gateway,
+            &ipv6_gateway,
+            metric,
+            rules,
+            priority,
+        ),
+        GenerateCommands::Bird {
+            ipv4_name,
+            ipv6_name,
+            protocol,
+            ipv4_nexthop,
+            ipv6_nexthop,
+        } => generate::bird(
+            cidr_pair,
+            &ipv4_name,
+            &ipv6_name,
+            &protocol,
+            ipv4_nexthop.as_deref(),
+            ipv6_nexthop.as_deref(),
+        ),
+        GenerateCommands::Frr {
+            name,
+            ipv4_nexthop,
+            ipv6_nexthop,
+            table,
+        } => generate::frr(
+            cidr_pair,
+            &name,
+            ipv4_nexthop.as_deref(),
+            ipv6_nexthop.as_deref(),
+            table,
+        ),
+        GenerateCommands::Routeros {
+            list,
+            comment,
+            ipv4_gateway,
+            ipv6_gateway,
+            table,
+        } => generate::routeros(
+            cidr_pair,
+            &list,
+            &comment,
+            ipv4_gateway.as_deref(),
+            ipv6_gateway.as_deref(),
+            &table,
+        ),
+        GenerateCommands::Openwrt {
+            ipv4set,
+            ipv6set,
+            loadfile,
+            entries,
+        } => generate::openwrt(
+            cidr_pair,
+            &ipv4set,
+            &ipv6set,
+            loadfile.as_deref(),
+            entries.as_deref(),
+        ),
+        GenerateCommands::Firewalld { ipv6 } => generate::firewalld(cidr_pair, ipv6),
+        GenerateCommands::Pf { table, alias } => generate::pf(cidr_pair, &table, alias),
+        GenerateCommands::Wireguard {
+            exclude,
+            keep_private,
+            public_key,
+            endpoint,
+        } => generate::wireguard(
+            cidr_pair,
+            &exclude
+                .iter()
+                .map(|elem| cidr::parse(elem))
+                .collect::<Result<Vec<_>>>()?,
+            keep_private,
+            public_key.as_deref(),
+            endpoint.as_deref(),
+        ),
+        GenerateCommands::Openvpn {
+            ipv4_gateway,
+            ipv6_gateway,
+            push,
+        } => generate::openvpn(cidr_pair, &ipv4_gateway, ipv6_gateway.as_deref(), push),
+        GenerateCommands::Tc {
+            dev,
+            parent,
+            classid,
+            prio,
+            src,
+            u32,
+        } => generate::tc(cidr_pair, &dev, &parent, &classid, prio, src, u32),
+        GenerateCommands::Nftables { .. } | GenerateCommands::Iproute2 { .. } => unreachable!(),
+    }
+}
+
+fn convert_with(
+    cidr_pair: (Vec<geoip::Cidr>, Vec<geoip::Cidr>),
+    country_code: &str,
+    command: ConvertCommands,
+) -> Result<Box<[u8]>> {
+    match command {
+        ConvertCommands::Text { ipv4, ipv6 } => {
+            let (mut ipv4_cidrs, mut ipv6_cidrs) = cidr_pair;
+            if ipv4 {
+                ipv6_cidrs.clear();
+            } else if ipv6 {
+                ipv4_cidrs.clear();
+            }
+            convert::to_text((ipv4_cidrs, ipv6_cidrs))
+        }
+        ConvertCommands::SrsJson {} => convert::to_srs_json(cidr_pair),
+        ConvertCommands::Clash { format } => match format {
+            ClashFormat::Yaml => convert::to_clash_yaml(cidr_pair),
+            ClashFormat::Text => convert::to_text(cidr_pair),
+            ClashFormat::Mrs => convert::to_mrs(cidr_pair),
+        },
+        c @ (ConvertCommands::Mmdb {} | ConvertCommands::Geofeed { .. }) => {
+            let (ipv4_cidrs, ipv6_cidrs) = cidr_pair;
+            convert_countries(
+                vec![(
+                    Box::from(country_code),
+                    ipv4_cidrs.into_iter().chain(ipv6_cidrs).collect(),
+                )],
+                c,
+            )
+        }
+        ConvertCommands::Range {} => convert::to_ranges(cidr_pair),
+        ConvertCommands::Csv {} => convert::to_csv(cidr_pair, country_code),
+        ConvertCommands::Json {} => convert::to_json(cidr_pair, country_code),
+        ConvertCommands::Srs {} | ConvertCommands::Ray {} => unreachable!(),
+    }
+}
+
+/// Outputs holding several countries, every one of a geoip.dat without --code.
+fn convert_countries(
+    countries: Vec<(Box<str>, Vec<geoip::Cidr>)>,
+    command: ConvertCommands,
+) -> Result<Box<[u8]>> {
+    match command {
+        ConvertCommands::Mmdb {} => convert::to_mmdb(countries),
+        ConvertCommands::Geofeed {
+            set_region,
+            set_city,
+        } => convert::to_geofeed(countries, set_region.as_deref(), set_city.as_deref()),
+        _ => unreachable!(),
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[allow(clippy::too_many_arguments)]
+fn apply_rules(
+    cidr_pair: (Vec<geoip::Cidr>, Vec<geoip::Cidr>),
+    dry_run: bool,
+    nftables: bool,
+    (nf_table, nf_ipv4set, nf_ipv6set): (&str, &str, &str),
+    iproute2_rule: bool,
+    delete: bool,
+    ru_table: &str,
+    r_table: &str,
+    r_ipv4_gateway: &str,
+    r_ipv6_gateway: &str,
+    r_dev: &str,
+) -> Result<Box<str>> {
+    if nftables {
+        apply::nftables(cidr_pair, nf_table, nf_ipv4set, nf_ipv6set, dry_run)
+    } else if iproute2_rule {
+        apply::iproute2rule(cidr_pair, delete, ru_table, dry_run)
+    } else {
+        apply::iproute2route(
+            cidr_pair,
+            delete,
+            r_table,
+            r_ipv4_gateway,
+            r_ipv6_gateway,
+            r_dev,
+            dry_run,
+        )
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+#[allow(clippy::too_many_arguments)]
+fn apply_rules(
+    _: (Vec<geoip::Cidr>, Vec<geoip::Cidr>),
+    _: bool,
+    _: bool,
+    _: (&str, &str, &str),
+    _: bool,
+    _: bool,
+    _: &str,
+    _: &str,
+    _: &str,
+    _: &str,
+    _: &str,
+) -> Result<Box<str>> {
+    anyhow::bail!("apply is only supported on Linux")
+}
@@ -0,0 +1,567 @@
+use crate::{cidr, geoip::Cidr};
+use anyhow::{bail, Context as _, Ok, Result};
+use serde_json::{json, Map, Value};
+
+use std::collections::HashMap;
+
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+/// The metadata is stored within the last 128KiB of the file.
+const METADATA_MAX_SIZE: usize = 128 * 1024;
+/// Zero bytes between the search tree and the data section.
+const DATA_SEPARATOR: usize = 16;
+/// Nesting limit for pointers, maps and arrays, as in libmaxminddb, so that a
+/// pointer loop or a crafted file can't overflow the stack.
+const MAX_DEPTH: usize = 512;
+
+const TYPE_POINTER: u8 = 1;
+const TYPE_STRING: u8 = 2;
+const TYPE_DOUBLE: u8 = 3;
+const TYPE_BYTES: u8 = 4;
+const TYPE_UINT16: u8 = 5;
+const TYPE_UINT32: u8 = 6;
+const TYPE_MAP: u8 = 7;
+const TYPE_INT32: u8 = 8;
+const TYPE_UINT64: u8 = 9;
+const TYPE_UINT128: u8 = 10;
+const TYPE_ARRAY: u8 = 11;
+const TYPE_BOOLEAN: u8 = 14;
+const TYPE_FLOAT: u8 = 15;
+
+fn metadata_start(buffer: &[u8]) -> Option<usize> {
+    let tail = buffer.len().saturating_sub(METADATA_MAX_SIZE);
+    buffer[tail..]
+        .windows(METADATA_MARKER.len())
+        .rposition(|window| window == METADATA_MARKER)
+        .map(|position| tail + position + METADATA_MARKER.len())
+}
+
+pub fn is_mmdb(buffer: &[u8]) -> bool {
+    metadata_start(buffer).is_some()
+}
+
+fn bytes(section: &[u8], offset: usize, len: usize) -> Result<&[u8]> {
+    section
+        .get(offset..offset + len)
+        .context("MMDB data out of bounds")
+}
+
+fn uint(data: &[u8]) -> u128 {
+    data.iter().fold(0, |value, &b| value << 8 | u128::from(b))
+}
+
+/// Decodes the value at `offset` of a data section, returning it with the offset
+/// following it. Pointers are followed, `depth` counts how deep this value is nested.
+fn decode(section: &[u8], offset: usize, depth: usize) -> Result<(Value, usize)> {
+    if depth > MAX_DEPTH {
+        bail!("MMDB data nested deeper than {}", MAX_DEPTH);
+    }
+    let control = *bytes(section, offset, 1)?.first().unwrap_or(&0);
+    let mut offset = offset + 1;
+    let mut ty = control >> 5;
+    if ty == TYPE_POINTER {
+        let len = usize::from(control >> 3 & 0x3) + 1;
+        let data = uint(bytes(section, offset, len)?) as usize;
+        let low = usize::from(control & 0x7);
+        let pointer = match len {
+            1 => low << 8 | data,
+            2 => (low << 16 | data) + 2048,
+            3 => (low << 24 | data) + 526336,
+            _ => data,
+        };
+        let (value, _) = decode(section, pointer, depth + 1)?;
+        return Ok((value, offset + len));
+    }
+    if ty == 0 {
+        ty = 7 + bytes(section, offset, 1)?[0];
+        offset += 1;
+    }
+    let mut size = usize::from(control & 0x1f);
+    if size >= 29 {
+        let len = size - 28;
+        let data = uint(bytes(section, offset, len)?) as usize;
+        offset += len;
+        size = match len {
+            1 => 29 + data,
+            2 => 285 + data,
+            _ => 65821 + data,
+        };
+    }
+    let value = match ty {
+        TYPE_STRING => {
+            let data = bytes(section, offset, size)?;
+            offset += size;
+            Value::from(String::from_utf8_lossy(data))
+        }
+        TYPE_DOUBLE | TYPE_FLOAT => {
+            let data = bytes(section, offset, size)?;
+            offset += size;
+            match size {
+                8 => Value::from(f64::from_be_bytes(data.try_into()?)),
+                4 => Value::from(f32::from_be_bytes(data.try_into()?)),
+                _ => bail!("Invalid MMDB float size: {}", size),
+            }
+        }
+        TYPE_BYTES => {
+            let data = bytes(section, offset, size)?;
+            offset += size;
+            Value::from(data.to_vec())
+        }
+        TYPE_UINT16 | TYPE_UINT32 | TYPE_UINT64 | TYPE_UINT128 => {
+            let value = uint(bytes(section, offset, size)?);
+            offset += size;
+            match u64::try_from(value) {
+                std::result::Result::Ok(value) => Value::from(value),
+                Err(_) => Value::from(value.to_string()),
+            }
+        }
+        TYPE_INT32 => {
+            let value = uint(bytes(section, offset, size)?) as u32 as i32;
+            offset += size;
+            Value::from(value)
+        }
+        TYPE_MAP => {
+            let mut map = Map::new();
+            for _ in 0..size {
+                let (key, next) = decode(section, offset, depth + 1)?;
+                let (value, next) = decode(section, next, depth + 1)?;
+                offset = next;
+                let Value::String(key) = key else {
+                    bail!("Invalid MMDB map key: {}", key);
+                };
+                map.insert(key, value);
+            }
+            Value::Object(map)
+        }
+        TYPE_ARRAY => {
+            let mut array = Vec::with_capacity(size);
+            for _ in 0..size {
+                let (value, next) = decode(section, offset, depth + 1)?;
+                offset = next;
+                array.push(value);
+            }
+            Value::Array(array)
+        }
+        TYPE_BOOLEAN => Value::from(size != 0),
+        _ => bail!("Unsupported MMDB data type: {}", ty),
+    };
+    Ok((value, offset))
+}
+
+fn metadata_u64(metadata: &Value, key: &str) -> Result<u64> {
+    metadata[key]
+        .as_u64()
+        .with_context(|| format!("Invalid MMDB metadata, no {}", key))
+}
+
+/// The country code of a record: `country.iso_code` as in GeoLite2 and DB-IP, or
+/// a plain `country` string as in some other vendors' databases.
+fn record_country(record: &Value, registered_country: bool) -> Option<&str> {
+    let key = if registered_country {
+        "registered_country"
+    } else {
+        "country"
+    };
+    match &record[key] {
+        Value::String(code) => Some(code),
+        country => country["iso_code"].as_str(),
+    }
+}
+
+/// Extracts all networks whose country matches `country_code` from a MaxMind DB,
+/// merging adjacent ones.
+pub fn read(
+    buffer: &[u8],
+    country_code: &str,
+    registered_country: bool,
+) -> Result<(Vec<Cidr>, Vec<Cidr>)> {
+    let metadata_start = metadata_start(buffer).context("No MMDB metadata found")?;
+    let (metadata, _) = decode(&buffer[metadata_start..], 0, 0)?;
+    let node_count = metadata_u64(&metadata, "node_count")? as usize;
+    let record_size = metadata_u64(&metadata, "record_size")? as usize;
+    let bit_count = match metadata_u64(&metadata, "ip_version")? {
+        4 => 32,
+        6 => 128,
+        version => bail!("Invalid MMDB ip_version: {}", version),
+    };
+    if ![24, 28, 32].contains(&record_size) {
+        bail!("Unsupported MMDB record size: {}", record_size);
+    }
+    let tree_size = node_count * record_size / 4;
+    let Some(data) = buffer.get(tree_size + DATA_SEPARATOR..metadata_start) else {
+        bail!("Invalid MMDB, search tree exceeds the file");
+    };
+
+    let record = |node: usize, right: bool| -> Result<usize> {
+        let node_bytes = bytes(buffer, node * record_size / 4, record_size / 4)?;
+        let value = match (record_size, right) {
+            (28, false) => u128::from(node_bytes[3] & 0xf0) << 20 | uint(&node_bytes[..3]),
+            (28, true) => u128::from(node_bytes[3] & 0x0f) << 24 | uint(&node_bytes[4..]),
+            (_, false) => uint(&node_bytes[..record_size / 8]),
+            (_, true) => uint(&node_bytes[record_size / 8..]),
+        };
+        Ok(value as usize)
+    };
+
+    // IPv4 lives at ::/96 in IPv6 databases, ::ffff:0:0/96 and 2002::/16 usually
+    // point back to it and must not be walked twice.
+    let mut ipv4_start = 0;
+    if bit_count == 128 {
+        for _ in 0..96 {
+            if ipv4_start >= node_count {
+                break;
+            }
+            ipv4_start = record(ipv4_start, false)?;
+        }
+    }
+
+    let mut codes: HashMap<usize, bool> = HashMap::new();
+    let (mut ipv4_cidrs, mut ipv6_cidrs) = (Vec::new(), Vec::new());
+    let mut stack = vec![(0_usize, 0_u32, 0_u128)];
+    while let Some((node, depth, addr)) = stack.pop() {
+        for right in [true, false] {
+            let depth = depth + 1;
+            let addr = if right {
+                addr | 1 << (bit_count - depth)
+            } else {
+                addr
+            };
+            let next = record(node, right)?;
+            if next < node_count {
+                if bit_count == 128 && next == ipv4_start && (depth, addr) != (96, 0) {
+                    continue;
+                }
+                stack.push((next, depth, addr));
+                continue;
+            }
+            if next == node_count {
+                continue;
+            }
+            let Some(offset) = next.checked_sub(node_count + DATA_SEPARATOR) else {
+                bail!("Invalid MMDB record: {}", next);
+            };
+            let matched = match codes.get(&offset) {
+                Some(&matched) => matched,
+                None => {
+                    let (record, _) = decode(data, offset, 0)?;
+                    let matched = record_country(&record, registered_country)
+                        .is_some_and(|code| code.eq_ignore_ascii_case(country_code));
+                    codes.insert(offset, matched);
+                    matched
+                }
+            };
+            if !matched {
+                continue;
+            }
+            if bit_count == 32 {
+                ipv4_cidrs.push(Cidr {
+                    ip: (addr as u32).to_be_bytes().to_vec(),
+                    prefix: depth,
+                });
+            } else if depth >= 96 && addr >> 32 == 0 {
+                ipv4_cidrs.push(Cidr {
+                    ip: (addr as u32).to_be_bytes().to_vec(),
+                    prefix: depth - 96,
+                });
+            } else {
+                ipv6_cidrs.push(Cidr {
+                    ip: addr.to_be_bytes().to_vec(),
+                    prefix: depth,
+                });
+            }
+        }
+    }
+
+    Ok((cidr::aggregate(&ipv4_cidrs)?, cidr::aggregate(&ipv6_cidrs)?))
+}
+
+fn encode_control(data: &mut Vec<u8>, ty: u8, size: usize) {
+    let (first, extended) = if ty > 7 {
+        (0, Some(ty - 7))
+    } else {
+        (ty << 5, None)
+    };
+    let (size_bits, size_bytes) = match size {
+        0..=28 => (size as u8, vec![]),
+        29..=284 => (29, vec![(size - 29) as u8]),
+        285..=65820 => (30, ((size - 285) as u16).to_be_bytes().to_vec()),
+        _ => (31, ((size - 65821) as u32).to_be_bytes()[1..].to_vec()),
+    };
+    data.push(first | size_bits);
+    data.extend(extended);
+    data.extend(size_bytes);
+}
+
+/// Encodes an unsigned integer as `ty`, with as few bytes as the value needs.
+fn encode_uint(data: &mut Vec<u8>, ty: u8, number: u64) {
+    let bytes = number.to_be_bytes();
+    let bytes = &bytes[(number.leading_zeros() / 8) as usize..];
+    encode_control(data, ty, bytes.len());
+    data.extend_from_slice(bytes);
+}
+
+/// Encodes a value into the data section format. Numbers become the smallest of
+/// uint16, uint32 and uint64, metadata fields with a fixed type use [`encode_uint`].
+fn encode(data: &mut Vec<u8>, value: &Value) -> Result<()> {
+    match value {
+        Value::String(string) => {
+            encode_control(data, TYPE_STRING, string.len());
+            data.extend_from_slice(string.as_bytes());
+        }
+        Value::Number(number) => {
+            let Some(number) = number.as_u64() else {
+                bail!("Unsupported MMDB number: {}", number);
+            };
+            let ty = match number {
+                0..=0xffff => TYPE_UINT16,
+                0x10000..=0xffff_ffff => TYPE_UINT32,
+                _ => TYPE_UINT64,
+            };
+            encode_uint(data, ty, number);
+        }
+        Value::Bool(boolean) => encode_control(data, TYPE_BOOLEAN, usize::from(*boolean)),
+        Value::Object(map) => {
+            encode_control(data, TYPE_MAP, map.len());
+            for (key, value) in map {
+                encode(data, &Value::from(key.as_str()))?;
+                encode(data, value)?;
+            }
+        }
+        Value::Array(array) => {
+            encode_control(data, TYPE_ARRAY, array.len());
+            for value in array {
+                encode(data, value)?;
+            }
+        }
+        Value::Null => bail!("Unsupported MMDB value: null"),
+    }
+    Ok(())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Record {
+    Empty,
+    Node(usize),
+    Data(usize),
+}
+
+/// The node holding the record of `addr/prefix`, splitting records on the way
+/// into nodes whose both halves keep the record.
+fn parent(nodes: &mut Vec<[Record; 2]>, addr: u128, prefix: u32) -> usize {
+    let mut node = 0;
+    for depth in 0..prefix - 1 {
+        let bit = (addr >> (127 - depth) & 1) as usize;
+        node = match nodes[node][bit] {
+            Record::Node(next) => next,
+            record => {
+                nodes.push([record; 2]);
+                nodes[node][bit] = Record::Node(nodes.len() - 1);
+                nodes.len() - 1
+            }
+        };
+    }
+    node
+}
+
+/// Builds an IPv6 MaxMind DB where every network maps to `{"country": {"iso_code"}}`.
+/// IPv4 networks are stored at `::/96` and aliased at `::ffff:0:0/96`, like
+/// MaxMind's own databases, so lookups of both forms work.
+pub fn write(countries: &[(Box<str>, Vec<Cidr>)]) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut networks = Vec::new();
+    for (index, (code, cidrs)) in countries.iter().enumerate() {
+        let offset = data.len();
+        encode(
+            &mut data,
+            &json!({ "country": { "iso_code": code.to_ascii_uppercase() } }),
+        )?;
+        for cidr in cidrs {
+            let range = cidr::Range::from_cidr(cidr)?;
+            let prefix = cidr.prefix + if range.len == 4 { 96 } else { 0 };
+            networks.push((prefix, range.start, offset, index));
+        }
+    }
+    // More specific networks are inserted last, so they win over the ones they
+    // are part of; so does the later country for the same network.
+    networks.sort_by_key(|&(prefix, _, _, index)| (prefix, index));
+
+    let mut nodes = vec![[Record::Empty; 2]];
+    for (prefix, addr, offset, _) in networks {
+        if prefix == 0 {
+            bail!("MMDB can't hold ::/0 or 0.0.0.0/0");
+        }
+        let node = parent(&mut nodes, addr, prefix);
+        nodes[node][(addr >> (128 - prefix) & 1) as usize] = Record::Data(offset);
+    }
+    // Find the IPv4 subtree and make ::ffff:0:0/96 point to it too.
+    let mut ipv4_start = Record::Node(0);
+    for _ in 0..96 {
+        if let Record::Node(node) = ipv4_start {
+            ipv4_start = nodes[node][0];
+        }
+    }
+    if let Record::Node(_) = ipv4_start {
+        let node = parent(&mut nodes, 0xffff << 32, 96);
+        nodes[node][1] = ipv4_start;
+    }
+
+    let node_count = nodes.len();
+    let max_record = node_count + DATA_SEPARATOR + data.len();
+    let record_size = match max_record {
+        0..=0xff_ffff => 24,
+        0x100_0000..=0xfff_ffff => 28,
+        _ => 32,
+    };
+    let value = |record: Record| -> u32 {
+        match record {
+            Record::Empty => node_count as u32,
+            Record::Node(node) => node as u32,
+            Record::Data(offset) => (node_count + DATA_SEPARATOR + offset) as u32,
+        }
+    };
+    let mut buffer = Vec::with_capacity(node_count * record_size / 4 + max_record);
+    for [left, right] in nodes {
+        let (left, right) = (value(left), value(right));
+        match record_size {
+            24 => {
+                buffer.extend_from_slice(&left.to_be_bytes()[1..]);
+                buffer.extend_from_slice(&right.to_be_bytes()[1..]);
+            }
+            28 => {
+                buffer.extend_from_slice(&left.to_be_bytes()[1..]);
+                buffer.push((left >> 24 << 4) as u8 | (right >> 24) as u8);
+                buffer.extend_from_slice(&right.to_be_bytes()[1..]);
+            }
+            _ => {
+                buffer.extend_from_slice(&left.to_be_bytes());
+                buffer.extend_from_slice(&right.to_be_bytes());
+            }
+        }
+    }
+    buffer.extend_from_slice(&[0; DATA_SEPARATOR]);
+    buffer.extend_from_slice(&data);
+    buffer.extend_from_slice(METADATA_MARKER);
+    let build_epoch = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    // libmaxminddb insists on these types, e.g. a uint16 node_count is rejected.
+    let uints = [
+        ("binary_format_major_version", TYPE_UINT16, 2),
+        ("binary_format_minor_version", TYPE_UINT16, 0),
+        ("build_epoch", TYPE_UINT64, build_epoch),
+        ("ip_version", TYPE_UINT16, 6),
+        ("node_count", TYPE_UINT32, node_count as u64),
+        ("record_size", TYPE_UINT16, record_size as u64),
+    ];
+    let others = [
+        ("database_type", json!("tsumugi-Country")),
+        ("description", json!({ "en": "Converted by tsumugi" })),
+        ("languages", json!(["en"])),
+    ];
+    encode_control(&mut buffer, TYPE_MAP, uints.len() + others.len());
+    for (key, ty, number) in uints {
+        encode(&mut buffer, &Value::from(key))?;
+        encode_uint(&mut buffer, ty, number);
+    }
+    for (key, value) in others {
+        encode(&mut buffer, &Value::from(key))?;
+        encode(&mut buffer, &value)?;
+    }
+    Ok(buffer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode, metadata_start, read, write, TYPE_MAP, TYPE_STRING, TYPE_UINT16, TYPE_UINT32,
+        TYPE_UINT64,
+    };
+    use crate::{cidr, generate::ToString as _, geoip::Cidr};
+
+    fn cidrs(cidrs: &[&str]) -> Vec<Cidr> {
+        cidrs
+            .iter()
+            .map(|cidr| cidr::parse(cidr).unwrap())
+            .collect()
+    }
+
+    fn strings(cidrs: &[Cidr]) -> Vec<String> {
+        cidrs.iter().map(|cidr| cidr.to_string().unwrap()).collect()
+    }
+
+    /// The type of the value at `offset`, from its control byte.
+    fn ty(section: &[u8], offset: usize) -> u8 {
+        match section[offset] >> 5 {
+            0 => 7 + section[offset + 1],
+            ty => ty,
+        }
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let buffer = write(&[
+            (
+                Box::from("cn"),
+                cidrs(&["10.0.0.0/8", "1.0.1.0/24", "2001:db8::/32"]),
+            ),
+            (
+                Box::from("us"),
+                cidrs(&["10.1.0.0/16", "2001:db8:1::/48", "2001:db9::/32"]),
+            ),
+        ])
+        .unwrap();
+
+        // The more specific US networks cut holes into the CN ones.
+        let (ipv4, ipv6) = read(&buffer, "US", false).unwrap();
+        assert_eq!(strings(&ipv4), ["10.1.0.0/16"]);
+        assert_eq!(strings(&ipv6), ["2001:db8:1::/48", "2001:db9::/32"]);
+        let (ipv4, ipv6) = read(&buffer, "cn", false).unwrap();
+        assert_eq!(
+            strings(&ipv4),
+            [
+                "1.0.1.0/24",
+                "10.0.0.0/16",
+                "10.2.0.0/15",
+                "10.4.0.0/14",
+                "10.8.0.0/13",
+                "10.16.0.0/12",
+                "10.32.0.0/11",
+                "10.64.0.0/10",
+                "10.128.0.0/9"
+            ]
+        );
+        assert_eq!(ipv6.len(), 16);
+        assert_eq!(strings(&ipv6[..2]), ["2001:db8::/48", "2001:db8:2::/47"]);
+        assert_eq!(strings(&ipv6[15..]), ["2001:db8:8000::/33"]);
+
+        let metadata = &buffer[metadata_start(&buffer).unwrap()..];
+        assert_eq!(ty(metadata, 0), TYPE_MAP);
+        let mut offset = 1;
+        let mut types = Vec::new();
+        while offset < metadata.len() {
+            let (key, next) = decode(metadata, offset, 0).unwrap();
+            let (_, after) = decode(metadata, next, 0).unwrap();
+            types.push((key.as_str().unwrap().to_owned(), ty(metadata, next)));
+            offset = after;
+        }
+        let ty_of = |name: &str| types.iter().find(|(key, _)| key == name).unwrap().1;
+        for key in [
+            "binary_format_major_version",
+            "binary_format_minor_version",
+            "ip_version",
+            "record_size",
+        ] {
+            assert_eq!(ty_of(key), TYPE_UINT16, "{key}");
+        }
+        assert_eq!(ty_of("node_count"), TYPE_UINT32);
+        assert_eq!(ty_of("build_epoch"), TYPE_UINT64);
+        assert_eq!(ty_of("database_type"), TYPE_STRING);
+        assert_eq!(ty_of("description"), TYPE_MAP);
+    }
+
+    #[test]
+    fn pointer_loop_is_rejected() {
+        // A pointer to offset 0, i.e. to itself.
+        let error = decode(&[0x20, 0x00], 0, 0).unwrap_err();
+        assert!(error.to_string().contains("nested deeper"));
+    }
+}
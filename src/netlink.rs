@@ -0,0 +1,296 @@
+use anyhow::{bail, Ok, Result};
+
+use std::{
+    io,
+    mem::{size_of, zeroed},
+    os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd},
+};
+
+const NLMSG_HDRLEN: usize = 16;
+const NLA_HDRLEN: usize = 4;
+const RECV_BUFFER_SIZE: usize = 64 * 1024;
+
+pub const NLM_F_REQUEST: u16 = 0x1;
+pub const NLM_F_ACK: u16 = 0x4;
+pub const NLM_F_EXCL: u16 = 0x200;
+pub const NLM_F_CREATE: u16 = 0x400;
+pub const NLM_F_DUMP: u16 = 0x300;
+
+const NLA_F_NESTED: u16 = 1 << 15;
+const NLA_TYPE_MASK: u16 = !(NLA_F_NESTED | 1 << 14);
+
+const NLMSG_ERROR: u16 = 0x2;
+const NLMSG_DONE: u16 = 0x3;
+
+const fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// A single netlink message, built up front and sent as part of a batch.
+pub struct Message {
+    buffer: Vec<u8>,
+}
+
+impl Message {
+    pub fn new(ty: u16, flags: u16) -> Self {
+        let mut buffer = vec![0; NLMSG_HDRLEN];
+        buffer[4..6].copy_from_slice(&ty.to_ne_bytes());
+        buffer[6..8].copy_from_slice(&flags.to_ne_bytes());
+        Self { buffer }
+    }
+
+    pub fn flags(&self) -> u16 {
+        u16::from_ne_bytes([self.buffer[6], self.buffer[7]])
+    }
+
+    /// Appends a protocol specific header, e.g. `rtmsg` or `nfgenmsg`.
+    pub fn header(&mut self, header: &[u8]) -> &mut Self {
+        self.buffer.extend_from_slice(header);
+        self.buffer.resize(align(self.buffer.len()), 0);
+        self
+    }
+
+    pub fn attr(&mut self, ty: u16, data: &[u8]) -> &mut Self {
+        let len = (NLA_HDRLEN + data.len()) as u16;
+        self.buffer.extend_from_slice(&len.to_ne_bytes());
+        self.buffer.extend_from_slice(&ty.to_ne_bytes());
+        self.buffer.extend_from_slice(data);
+        self.buffer.resize(align(self.buffer.len()), 0);
+        self
+    }
+
+    /// Opens a nested attribute, returns the offset to pass to [`Message::end_nested`].
+    pub fn begin_nested(&mut self, ty: u16) -> usize {
+        let offset = self.buffer.len();
+        self.buffer.extend_from_slice(&[0, 0]);
+        self.buffer
+            .extend_from_slice(&(ty | NLA_F_NESTED).to_ne_bytes());
+        offset
+    }
+
+    /// Closes a nested attribute, which fails once its content outgrows the `u16` length.
+    pub fn end_nested(&mut self, offset: usize) -> Result<&mut Self> {
+        let Some(len) = u16::try_from(self.buffer.len() - offset).ok() else {
+            bail!("Nested netlink attribute exceeds {} bytes", u16::MAX);
+        };
+        self.buffer[offset..offset + 2].copy_from_slice(&len.to_ne_bytes());
+        Ok(self)
+    }
+
+    fn finish(&mut self, seq: u32) {
+        let len = self.buffer.len() as u32;
+        self.buffer[0..4].copy_from_slice(&len.to_ne_bytes());
+        self.buffer[8..12].copy_from_slice(&seq.to_ne_bytes());
+    }
+
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+/// Iterates over the `(type, data)` attributes of a message payload, after its
+/// `header_len` bytes of protocol header.
+pub fn attrs(payload: &[u8], header_len: usize) -> impl Iterator<Item = (u16, &[u8])> {
+    let mut data = payload.get(align(header_len)..).unwrap_or_default();
+    std::iter::from_fn(move || {
+        if data.len() < NLA_HDRLEN {
+            return None;
+        }
+        let len = u16::from_ne_bytes([data[0], data[1]]) as usize;
+        let ty = u16::from_ne_bytes([data[2], data[3]]) & NLA_TYPE_MASK;
+        if len < NLA_HDRLEN || len > data.len() {
+            return None;
+        }
+        let attr = (ty, &data[NLA_HDRLEN..len]);
+        data = &data[align(len).min(data.len())..];
+        Some(attr)
+    })
+}
+
+pub struct Socket {
+    fd: OwnedFd,
+    seq: u32,
+}
+
+impl Socket {
+    pub fn open(protocol: libc::c_int) -> Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                protocol,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let timeout = libc::timeval {
+            tv_sec: 10,
+            tv_usec: 0,
+        };
+        unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const _ as *const libc::c_void,
+                size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+        let mut addr: libc::sockaddr_nl = unsafe { zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const _ as *const libc::sockaddr,
+                size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(Self { fd, seq: 1 })
+    }
+
+    /// Grows the send buffer so that a batch of `len` bytes fits into a single datagram.
+    fn reserve(&self, len: usize) {
+        let size = libc::c_int::try_from(len).unwrap_or(libc::c_int::MAX);
+        for option in [libc::SO_SNDBUFFORCE, libc::SO_SNDBUF] {
+            let ret = unsafe {
+                libc::setsockopt(
+                    self.fd.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    option,
+                    &size as *const _ as *const libc::c_void,
+                    size_of::<libc::c_int>() as libc::socklen_t,
+                )
+            };
+            if ret == 0 {
+                break;
+            }
+        }
+    }
+
+    /// Sends `messages` to the kernel in a single datagram and waits for an
+    /// acknowledgement of every message carrying `NLM_F_ACK`. An error reported
+    /// against a message without `NLM_F_ACK` means the whole batch was rejected.
+    ///
+    /// The returned vector holds the kernel's verdict for each message, in order.
+    pub fn batch(&mut self, messages: &mut [Message]) -> Result<Vec<io::Result<()>>> {
+        let first_seq = self.seq;
+        let mut buffer = Vec::with_capacity(messages.iter().map(Message::len).sum());
+        let mut pending = 0;
+        for message in messages.iter_mut() {
+            message.finish(self.seq);
+            self.seq = self.seq.wrapping_add(1);
+            if message.flags() & NLM_F_ACK != 0 {
+                pending += 1;
+            }
+            buffer.extend_from_slice(&message.buffer);
+        }
+        let mut results: Vec<io::Result<()>> =
+            messages.iter().map(|_| io::Result::Ok(())).collect();
+
+        self.reserve(buffer.len());
+        self.send(&buffer)?;
+
+        let mut recv_buffer = vec![0_u8; RECV_BUFFER_SIZE];
+        while pending > 0 {
+            let mut data = self.recv(&mut recv_buffer)?;
+            while data.len() >= NLMSG_HDRLEN {
+                let len = u32::from_ne_bytes(data[0..4].try_into()?) as usize;
+                let ty = u16::from_ne_bytes(data[4..6].try_into()?);
+                let seq = u32::from_ne_bytes(data[8..12].try_into()?);
+                if len < NLMSG_HDRLEN || len > data.len() {
+                    bail!("Truncated netlink message");
+                }
+                let index = seq.wrapping_sub(first_seq) as usize;
+                if ty == NLMSG_ERROR && index < results.len() && len >= NLMSG_HDRLEN + 4 {
+                    let error =
+                        i32::from_ne_bytes(data[NLMSG_HDRLEN..NLMSG_HDRLEN + 4].try_into()?);
+                    if error != 0 {
+                        results[index] = Err(io::Error::from_raw_os_error(-error));
+                    }
+                    if messages[index].flags() & NLM_F_ACK != 0 {
+                        pending -= 1;
+                    } else if error != 0 {
+                        pending = 0;
+                    }
+                }
+                data = &data[align(len).min(data.len())..];
+            }
+        }
+        Ok(results)
+    }
+    /// Sends a single `NLM_F_DUMP` request and collects the payload of every
+    /// reply, i.e. the protocol header followed by its attributes.
+    pub fn dump(&mut self, mut message: Message) -> Result<Vec<Vec<u8>>> {
+        let seq = self.seq;
+        message.finish(seq);
+        self.seq = self.seq.wrapping_add(1);
+        self.send(&message.buffer)?;
+
+        let mut payloads = Vec::new();
+        let mut recv_buffer = vec![0_u8; RECV_BUFFER_SIZE];
+        loop {
+            let mut data = self.recv(&mut recv_buffer)?;
+            while data.len() >= NLMSG_HDRLEN {
+                let len = u32::from_ne_bytes(data[0..4].try_into()?) as usize;
+                let ty = u16::from_ne_bytes(data[4..6].try_into()?);
+                if len < NLMSG_HDRLEN || len > data.len() {
+                    bail!("Truncated netlink message");
+                }
+                if u32::from_ne_bytes(data[8..12].try_into()?) == seq {
+                    match ty {
+                        NLMSG_DONE => return Ok(payloads),
+                        NLMSG_ERROR if len >= NLMSG_HDRLEN + 4 => {
+                            let error = i32::from_ne_bytes(
+                                data[NLMSG_HDRLEN..NLMSG_HDRLEN + 4].try_into()?,
+                            );
+                            if error != 0 {
+                                return Err(io::Error::from_raw_os_error(-error).into());
+                            }
+                        }
+                        _ => payloads.push(data[NLMSG_HDRLEN..len].to_vec()),
+                    }
+                }
+                data = &data[align(len).min(data.len())..];
+            }
+        }
+    }
+
+    fn send(&self, buffer: &[u8]) -> Result<()> {
+        let mut addr: libc::sockaddr_nl = unsafe { zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let sent = unsafe {
+            libc::sendto(
+                self.fd.as_raw_fd(),
+                buffer.as_ptr() as *const libc::c_void,
+                buffer.len(),
+                0,
+                &addr as *const _ as *const libc::sockaddr,
+                size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(())
+    }
+
+    fn recv<'a>(&self, buffer: &'a mut [u8]) -> Result<&'a [u8]> {
+        let received = unsafe {
+            libc::recv(
+                self.fd.as_raw_fd(),
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+                0,
+            )
+        };
+        if received < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(&buffer[..received as usize])
+    }
+}
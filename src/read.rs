@@ -1,11 +1,16 @@
 use crate::{
+    cidr::{self, Range},
+    convert::{MRS_BEHAVIOR_IPCIDR, MRS_IPCIDR_VERSION, MRS_MAGIC},
     geoip::{Cidr, GeoIpList},
-    srs,
+    mmdb, srs,
 };
 use anyhow::{bail, Context, Ok, Result};
 use prost::Message as _;
+use serde_json::Value;
 
+use std::collections::HashSet;
 use std::io::Read as _;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::path::PathBuf;
 use std::thread::sleep;
 use std::time::Duration;
@@ -31,9 +36,42 @@ impl ToCidr for srs::IPv6CIDR {
     }
 }
 
+/// Source options besides the country code, each used by the formats it names.
+#[derive(Debug, Default)]
+pub struct Options {
+    /// MMDB, GeoLite2 CSV: match the registered country instead of the country
+    pub registered_country: bool,
+    /// GeoLite2 CSV: path or url of the locations CSV, to map geoname ids to codes
+    pub locations: Option<Box<str>>,
+    /// RIR delegated stats: record statuses to select, e.g. `allocated`
+    pub statuses: Vec<Box<str>>,
+    /// Geofeed: ISO 3166-2 region to select besides the country
+    pub region: Option<Box<str>>,
+    /// BGP table dumps: origin ASNs to select prefixes by
+    pub asns: Vec<u32>,
+}
+
 const MAX_RETRIES: usize = 3;
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const BZIP2_MAGIC: [u8; 3] = *b"BZh";
+
+const MRT_TABLE_DUMP_V2: u16 = 13;
+const MRT_PEER_INDEX_TABLE: u16 = 1;
+const MRT_RIB_IPV4_UNICAST: u16 = 2;
+const MRT_RIB_IPV6_UNICAST: u16 = 4;
+const MRT_RIB_IPV4_UNICAST_ADDPATH: u16 = 8;
+const MRT_RIB_IPV6_UNICAST_ADDPATH: u16 = 10;
+const BGP_ATTR_FLAG_EXTENDED_LENGTH: u8 = 0x10;
+const BGP_ATTR_AS_PATH: u8 = 2;
+const AS_SET: u8 = 1;
+const AS_SEQUENCE: u8 = 2;
 
-fn from_buffer(buffer: &mut [u8], country_code: &str) -> Result<(Vec<Cidr>, Vec<Cidr>)> {
+fn from_buffer(
+    buffer: &mut [u8],
+    country_code: &str,
+    options: &Options,
+) -> Result<(Vec<Cidr>, Vec<Cidr>)> {
     {
         let result_ptr = unsafe { srs::read_cidr_rule(buffer.as_mut_ptr(), buffer.len() as u32) };
 
@@ -63,6 +101,55 @@ fn from_buffer(buffer: &mut [u8], country_code: &str) -> Result<(Vec<Cidr>, Vec<
             return Ok((ipv4_cidrs, ipv6_cidrs));
         }
     }
+    if buffer.starts_with(&GZIP_MAGIC) || buffer.starts_with(&BZIP2_MAGIC) {
+        let mut decompressed = Vec::new();
+        if buffer.starts_with(&GZIP_MAGIC) {
+            flate2::read::MultiGzDecoder::new(&*buffer).read_to_end(&mut decompressed)?;
+        } else {
+            bzip2::read::MultiBzDecoder::new(&*buffer).read_to_end(&mut decompressed)?;
+        }
+        return from_buffer(&mut decompressed, country_code, options);
+    }
+    if is_mrt(buffer) {
+        return from_mrt(buffer, options);
+    }
+    if mmdb::is_mmdb(buffer) {
+        return mmdb::read(buffer, country_code, options.registered_country);
+    }
+    if buffer.starts_with(&ZSTD_MAGIC) {
+        return from_mrs(buffer);
+    }
+    if let std::result::Result::Ok(Value::Object(rule_set)) =
+        serde_json::from_slice::<Value>(buffer)
+    {
+        if let Some(rules) = rule_set.get("rules") {
+            return from_rule_set(rules);
+        }
+    }
+    if let std::result::Result::Ok(text) = std::str::from_utf8(buffer) {
+        if let Some(payload) = clash_payload(text) {
+            return from_lines(payload);
+        }
+        if text.starts_with("TABLE_DUMP") {
+            return from_bgpdump(text, options);
+        }
+        if is_delegated(text) {
+            return from_delegated(text, country_code, options);
+        }
+        if text.starts_with("network,") {
+            return from_geolite2_csv(text, country_code, options);
+        }
+        if let Some(cidr_pair) = from_range_csv(text, country_code)? {
+            return Ok(cidr_pair);
+        }
+        if let Some(cidr_pair) = from_geofeed(text, country_code, options)? {
+            return Ok(cidr_pair);
+        }
+        // geoip.dat may happen to be valid UTF-8 too, so fall through on errors.
+        if let std::result::Result::Ok(cidr_pair) = from_lines(text.lines()) {
+            return Ok(cidr_pair);
+        }
+    }
     if country_code == "NULL" {
         bail!("country_code == \"NULL\"");
     }
@@ -78,7 +165,473 @@ fn from_buffer(buffer: &mut [u8], country_code: &str) -> Result<(Vec<Cidr>, Vec<
     Ok((ipv4_cidrs, ipv6_cidrs))
 }
 
-pub fn fetch(country_code: &str, url: &str) -> Result<(Vec<Cidr>, Vec<Cidr>)> {
+/// Collects `ip_cidr` of the rules of a sing-box source rule-set.
+fn from_rule_set(rules: &Value) -> Result<(Vec<Cidr>, Vec<Cidr>)> {
+    let Some(rules) = rules.as_array() else {
+        bail!("Invalid rule-set, rules is not an array");
+    };
+    let mut cidrs = Vec::new();
+    for rule in rules {
+        match rule.get("ip_cidr") {
+            Some(Value::String(elem)) => cidrs.push(cidr::parse(elem)?),
+            Some(Value::Array(elems)) => {
+                for elem in elems {
+                    let Some(elem) = elem.as_str() else {
+                        bail!("Invalid rule-set, ip_cidr is not a string: {}", elem);
+                    };
+                    cidrs.push(cidr::parse(elem)?);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(cidrs.into_iter().partition(|c| c.ip.len() == 4))
+}
+
+/// Parses a list with one CIDR per line, e.g. a Clash text rule-provider.
+/// Empty lines and `#` comments are skipped.
+fn from_lines<'a>(lines: impl Iterator<Item = &'a str>) -> Result<(Vec<Cidr>, Vec<Cidr>)> {
+    let mut cidrs = Vec::new();
+    for line in lines {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.contains('-') {
+            cidrs.extend(cidr::parse_range(line)?.to_cidrs());
+        } else if !line.is_empty() {
+            cidrs.push(cidr::parse(line)?);
+        }
+    }
+    Ok(cidrs.into_iter().partition(|c| c.ip.len() == 4))
+}
+
+/// The entries of the `payload` list of a Clash YAML rule-provider, if it is one.
+fn clash_payload(text: &str) -> Option<impl Iterator<Item = &str>> {
+    let mut lines = text
+        .lines()
+        .skip_while(|line| line.trim().is_empty() || line.starts_with('#'));
+    let flow = lines.next()?.strip_prefix("payload:")?.trim();
+    let entries: Vec<&str> = if let Some(flow) = flow.strip_prefix('[') {
+        flow.trim_end_matches(']').split(',').collect()
+    } else {
+        lines
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map_while(|line| line.strip_prefix('-'))
+            .collect()
+    };
+    Some(
+        entries
+            .into_iter()
+            .map(|entry| entry.trim().trim_matches(|c| c == '\'' || c == '"')),
+    )
+}
+
+/// RIR delegated stats start with a `version|registry|serial|...` header.
+fn is_delegated(text: &str) -> bool {
+    text.lines()
+        .find(|line| !line.starts_with('#') && !line.trim().is_empty())
+        .is_some_and(|line| line.split('|').count() >= 7)
+}
+
+/// RIR delegated stats, `registry|cc|type|start|value|date|status[|...]`. IPv4
+/// records count addresses and are split into the fewest CIDRs, IPv6 records
+/// give a prefix length.
+fn from_delegated(
+    text: &str,
+    country_code: &str,
+    options: &Options,
+) -> Result<(Vec<Cidr>, Vec<Cidr>)> {
+    let (mut ipv4_cidrs, mut ipv6_cidrs) = (Vec::new(), Vec::new());
+    for line in text.lines().filter(|line| !line.starts_with('#')) {
+        let fields: Vec<&str> = line.trim().split('|').collect();
+        let [_, cc, ty, start, value, _, status, ..] = fields[..] else {
+            continue;
+        };
+        if !cc.eq_ignore_ascii_case(country_code)
+            || !options
+                .statuses
+                .iter()
+                .any(|s| s.eq_ignore_ascii_case(status))
+        {
+            continue;
+        }
+        match ty {
+            "ipv4" => {
+                let start: Ipv4Addr = start
+                    .parse()
+                    .with_context(|| format!("Invalid delegated record: {}", line))?;
+                let count: u32 = value
+                    .parse()
+                    .with_context(|| format!("Invalid delegated record: {}", line))?;
+                let last = count
+                    .checked_sub(1)
+                    .and_then(|hosts| u32::from(start).checked_add(hosts));
+                let Some(last) = last else {
+                    bail!("Invalid delegated record: {}", line);
+                };
+                let range = Range::from_addrs(start.into(), Ipv4Addr::from(last).into())?;
+                ipv4_cidrs.extend(range.to_cidrs());
+            }
+            "ipv6" => ipv6_cidrs.push(cidr::parse(&format!("{}/{}", start, value))?),
+            _ => {}
+        }
+    }
+    Ok((cidr::aggregate(&ipv4_cidrs)?, cidr::aggregate(&ipv6_cidrs)?))
+}
+
+/// Splits a CSV line, fields may be double quoted.
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.trim_end_matches('\r').chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+/// The index of `name` in a CSV header.
+fn csv_column(header: &[String], name: &str) -> Result<usize> {
+    header
+        .iter()
+        .position(|field| field == name)
+        .with_context(|| format!("No {} column in CSV header", name))
+}
+
+/// GeoLite2/GeoIP2 country blocks CSV, `network,geoname_id,registered_country_geoname_id,...`,
+/// joined with the locations CSV for the ISO codes.
+fn from_geolite2_csv(
+    text: &str,
+    country_code: &str,
+    options: &Options,
+) -> Result<(Vec<Cidr>, Vec<Cidr>)> {
+    let Some(locations) = &options.locations else {
+        bail!("GeoLite2 CSV needs --locations, e.g. GeoLite2-Country-Locations-en.csv");
+    };
+    let locations = if locations.contains("://") {
+        download(locations)?
+    } else {
+        load(PathBuf::from(&**locations))?
+    };
+    let locations = String::from_utf8(locations).context("Invalid locations CSV")?;
+    let mut lines = locations.lines().map(csv_fields);
+    let header = lines.next().unwrap_or_default();
+    let (id_column, code_column) = (
+        csv_column(&header, "geoname_id")?,
+        csv_column(&header, "country_iso_code")?,
+    );
+    let geoname_ids: HashSet<String> = lines
+        .filter(|fields| {
+            fields
+                .get(code_column)
+                .is_some_and(|code| code.eq_ignore_ascii_case(country_code))
+        })
+        .filter_map(|fields| fields.get(id_column).cloned())
+        .collect();
+
+    let mut lines = text.lines().map(csv_fields);
+    let header = lines.next().unwrap_or_default();
+    let network_column = csv_column(&header, "network")?;
+    let id_column = csv_column(
+        &header,
+        if options.registered_country {
+            "registered_country_geoname_id"
+        } else {
+            "geoname_id"
+        },
+    )?;
+    let mut cidrs = Vec::new();
+    for fields in lines {
+        if fields
+            .get(id_column)
+            .is_some_and(|id| geoname_ids.contains(id))
+        {
+            cidrs.push(cidr::parse(&fields[network_column])?);
+        }
+    }
+    let (ipv4_cidrs, ipv6_cidrs): (Vec<Cidr>, Vec<Cidr>) =
+        cidrs.into_iter().partition(|c| c.ip.len() == 4);
+    Ok((cidr::aggregate(&ipv4_cidrs)?, cidr::aggregate(&ipv6_cidrs)?))
+}
+
+/// DB-IP style range CSV, `start,end,country` without a header, if it is one.
+/// Ranges are split into the fewest CIDRs.
+fn from_range_csv(text: &str, country_code: &str) -> Result<Option<(Vec<Cidr>, Vec<Cidr>)>> {
+    let range = |fields: &[String]| -> Option<(IpAddr, IpAddr)> {
+        Some((fields.first()?.parse().ok()?, fields.get(1)?.parse().ok()?))
+    };
+    let is_range_csv = text.lines().next().is_some_and(|line| {
+        let fields = csv_fields(line);
+        fields.len() == 3 && range(&fields).is_some()
+    });
+    if !is_range_csv {
+        return Ok(None);
+    }
+    let (mut ipv4_cidrs, mut ipv6_cidrs) = (Vec::new(), Vec::new());
+    for line in text.lines().filter(|line| !line.trim().is_empty()) {
+        let fields = csv_fields(line);
+        let Some((start, last)) = range(&fields) else {
+            bail!("Invalid range CSV line: {}", line);
+        };
+        if !fields[2].eq_ignore_ascii_case(country_code) {
+            continue;
+        }
+        let cidrs = Range::from_addrs(start, last)?.to_cidrs();
+        if start.is_ipv4() {
+            ipv4_cidrs.extend(cidrs);
+        } else {
+            ipv6_cidrs.extend(cidrs);
+        }
+    }
+    Ok(Some((
+        cidr::aggregate(&ipv4_cidrs)?,
+        cidr::aggregate(&ipv6_cidrs)?,
+    )))
+}
+
+/// An RFC 8805 geofeed, `prefix,country,region,city,postal`, if it is one.
+fn from_geofeed(
+    text: &str,
+    country_code: &str,
+    options: &Options,
+) -> Result<Option<(Vec<Cidr>, Vec<Cidr>)>> {
+    let mut lines = text
+        .lines()
+        .filter(|line| !line.starts_with('#') && !line.trim().is_empty())
+        .map(csv_fields);
+    let is_geofeed = lines
+        .clone()
+        .next()
+        .is_some_and(|fields| fields.len() >= 2 && cidr::parse(&fields[0]).is_ok());
+    if !is_geofeed {
+        return Ok(None);
+    }
+    let mut cidrs = Vec::new();
+    for fields in &mut lines {
+        let [prefix, country, rest @ ..] = &fields[..] else {
+            bail!("Invalid geofeed line: {}", fields.join(","));
+        };
+        let region = rest.first().map_or("", |region| region.trim());
+        if country.trim().eq_ignore_ascii_case(country_code)
+            && options
+                .region
+                .as_ref()
+                .is_none_or(|selected| selected.eq_ignore_ascii_case(region))
+        {
+            cidrs.push(cidr::parse(prefix)?);
+        }
+    }
+    let (ipv4_cidrs, ipv6_cidrs): (Vec<Cidr>, Vec<Cidr>) =
+        cidrs.into_iter().partition(|c| c.ip.len() == 4);
+    Ok(Some((
+        cidr::aggregate(&ipv4_cidrs)?,
+        cidr::aggregate(&ipv6_cidrs)?,
+    )))
+}
+
+fn take<'a>(reader: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if reader.len() < len {
+        bail!("Truncated data");
+    }
+    let (head, tail) = reader.split_at(len);
+    *reader = tail;
+    Ok(head)
+}
+
+fn take_i64(reader: &mut &[u8]) -> Result<i64> {
+    Ok(i64::from_be_bytes(take(reader, 8)?.try_into()?))
+}
+
+fn take_u16(reader: &mut &[u8]) -> Result<u16> {
+    Ok(u16::from_be_bytes(take(reader, 2)?.try_into()?))
+}
+
+fn take_u32(reader: &mut &[u8]) -> Result<u32> {
+    Ok(u32::from_be_bytes(take(reader, 4)?.try_into()?))
+}
+
+/// Parses `4134` or `AS4134`.
+pub fn parse_asn(asn: &str) -> Result<u32> {
+    let asn = asn.trim();
+    let number = match asn.get(..2) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("as") => &asn[2..],
+        _ => asn,
+    };
+    number
+        .parse()
+        .with_context(|| format!("Invalid ASN: {}", asn))
+}
+
+fn selected_asns(options: &Options) -> Result<&[u32]> {
+    if options.asns.is_empty() {
+        bail!("BGP table dumps need --asn to select prefixes by origin");
+    }
+    Ok(&options.asns)
+}
+
+/// `bgpdump -m` output, `TABLE_DUMP2|time|B|peer|peer_as|prefix|as_path|...`.
+/// The origin is the last AS of the path, or every AS of a trailing AS set.
+fn from_bgpdump(text: &str, options: &Options) -> Result<(Vec<Cidr>, Vec<Cidr>)> {
+    let asns = selected_asns(options)?;
+    let mut cidrs = Vec::new();
+    for line in text.lines() {
+        let fields: Vec<&str> = line.split('|').collect();
+        let [_, _, _, _, _, prefix, as_path, ..] = fields[..] else {
+            continue;
+        };
+        let Some(origin) = as_path.split_whitespace().last() else {
+            continue;
+        };
+        let origins = origin.trim_matches(|c| c == '{' || c == '}').split(',');
+        // Confederation segments, e.g. `[65001 65002]`, don't name an origin.
+        for origin in origins {
+            let std::result::Result::Ok(origin) = parse_asn(origin) else {
+                continue;
+            };
+            if asns.contains(&origin) {
+                cidrs.push(cidr::parse(prefix)?);
+                break;
+            }
+        }
+    }
+    let (ipv4_cidrs, ipv6_cidrs): (Vec<Cidr>, Vec<Cidr>) =
+        cidrs.into_iter().partition(|c| c.ip.len() == 4);
+    Ok((cidr::aggregate(&ipv4_cidrs)?, cidr::aggregate(&ipv6_cidrs)?))
+}
+
+/// MRT TABLE_DUMP_V2 files start with a peer index table.
+fn is_mrt(buffer: &[u8]) -> bool {
+    buffer.len() >= 12
+        && u16::from_be_bytes([buffer[4], buffer[5]]) == MRT_TABLE_DUMP_V2
+        && u16::from_be_bytes([buffer[6], buffer[7]]) == MRT_PEER_INDEX_TABLE
+}
+
+/// The origin ASes of the AS_PATH in BGP path attributes, see [`from_bgpdump`].
+fn origins(mut attributes: &[u8]) -> Result<Vec<u32>> {
+    let mut origins = Vec::new();
+    while !attributes.is_empty() {
+        let flags = take(&mut attributes, 1)?[0];
+        let ty = take(&mut attributes, 1)?[0];
+        let len = if flags & BGP_ATTR_FLAG_EXTENDED_LENGTH != 0 {
+            take_u16(&mut attributes)?.into()
+        } else {
+            take(&mut attributes, 1)?[0].into()
+        };
+        let mut value = take(&mut attributes, len)?;
+        if ty != BGP_ATTR_AS_PATH {
+            continue;
+        }
+        // TABLE_DUMP_V2 always carries 4 byte ASNs.
+        while !value.is_empty() {
+            let segment = take(&mut value, 1)?[0];
+            let count = take(&mut value, 1)?[0];
+            let mut asns = take(&mut value, usize::from(count) * 4)?
+                .chunks(4)
+                .map(|asn| u32::from_be_bytes([asn[0], asn[1], asn[2], asn[3]]));
+            match segment {
+                AS_SEQUENCE if count > 0 => origins = asns.next_back().into_iter().collect(),
+                AS_SET => origins = asns.collect(),
+                _ => {}
+            }
+        }
+    }
+    Ok(origins)
+}
+
+/// An uncompressed MRT RIB dump (RFC 6396 TABLE_DUMP_V2), selecting prefixes
+/// announced with one of the ASNs as origin by any peer.
+fn from_mrt(buffer: &[u8], options: &Options) -> Result<(Vec<Cidr>, Vec<Cidr>)> {
+    let asns = selected_asns(options)?;
+    let (mut ipv4_cidrs, mut ipv6_cidrs) = (Vec::new(), Vec::new());
+    let mut reader = buffer;
+    while !reader.is_empty() {
+        let header = take(&mut reader, 12)?;
+        let ty = u16::from_be_bytes([header[4], header[5]]);
+        let subtype = u16::from_be_bytes([header[6], header[7]]);
+        let len = u32::from_be_bytes([header[8], header[9], header[10], header[11]]);
+        let mut message = take(&mut reader, len as usize)?;
+        if ty != MRT_TABLE_DUMP_V2 {
+            continue;
+        }
+        let (len, add_path) = match subtype {
+            MRT_RIB_IPV4_UNICAST => (4, false),
+            MRT_RIB_IPV6_UNICAST => (16, false),
+            MRT_RIB_IPV4_UNICAST_ADDPATH => (4, true),
+            MRT_RIB_IPV6_UNICAST_ADDPATH => (16, true),
+            _ => continue,
+        };
+        let _sequence = take_u32(&mut message)?;
+        let prefix = u32::from(take(&mut message, 1)?[0]);
+        if prefix > len as u32 * 8 {
+            bail!("Invalid MRT prefix length: {}", prefix);
+        }
+        let mut ip = vec![0; len];
+        let prefix_bytes = take(&mut message, prefix.div_ceil(8) as usize)?;
+        ip[..prefix_bytes.len()].copy_from_slice(prefix_bytes);
+        let mut matched = false;
+        for _ in 0..take_u16(&mut message)? {
+            let _peer_index = take_u16(&mut message)?;
+            let _originated_time = take_u32(&mut message)?;
+            if add_path {
+                let _path_id = take_u32(&mut message)?;
+            }
+            let len = take_u16(&mut message)?;
+            let attributes = take(&mut message, len.into())?;
+            if !matched && origins(attributes)?.iter().any(|asn| asns.contains(asn)) {
+                matched = true;
+            }
+        }
+        if matched {
+            let cidrs = if len == 4 {
+                &mut ipv4_cidrs
+            } else {
+                &mut ipv6_cidrs
+            };
+            cidrs.push(Cidr { ip, prefix });
+        }
+    }
+    Ok((cidr::aggregate(&ipv4_cidrs)?, cidr::aggregate(&ipv6_cidrs)?))
+}
+
+/// Reads a mihomo binary rule-set with `behavior: ipcidr`, see `convert::to_mrs`.
+fn from_mrs(buffer: &[u8]) -> Result<(Vec<Cidr>, Vec<Cidr>)> {
+    let data = zstd::decode_all(buffer).context("Failed to decompress MRS rule-set")?;
+    let mut reader = data.as_slice();
+    if take(&mut reader, 4)? != MRS_MAGIC {
+        bail!("Not a MRS rule-set");
+    }
+    if take(&mut reader, 1)?[0] != MRS_BEHAVIOR_IPCIDR {
+        bail!("Only MRS rule-sets with ipcidr behavior are supported");
+    }
+    let _count = take_i64(&mut reader)?;
+    let extra = usize::try_from(take_i64(&mut reader)?).context("Invalid MRS extra length")?;
+    take(&mut reader, extra)?;
+    if take(&mut reader, 1)?[0] != MRS_IPCIDR_VERSION {
+        bail!("Unsupported MRS ipcidr version");
+    }
+    let len = usize::try_from(take_i64(&mut reader)?).context("Invalid MRS range count")?;
+    let (mut ipv4_cidrs, mut ipv6_cidrs) = (Vec::new(), Vec::new());
+    for _ in 0..len {
+        let start = Ipv6Addr::from(<[u8; 16]>::try_from(take(&mut reader, 16)?)?);
+        let last = Ipv6Addr::from(<[u8; 16]>::try_from(take(&mut reader, 16)?)?);
+        match (start.to_ipv4_mapped(), last.to_ipv4_mapped()) {
+            (Some(start), Some(last)) => {
+                ipv4_cidrs.extend(Range::from_addrs(start.into(), last.into())?.to_cidrs())
+            }
+            _ => ipv6_cidrs.extend(Range::from_addrs(start.into(), last.into())?.to_cidrs()),
+        }
+    }
+    Ok((ipv4_cidrs, ipv6_cidrs))
+}
+
+fn download(url: &str) -> Result<Vec<u8>> {
     let mut buffer = Vec::new();
     let mut retries = 0;
 
@@ -115,11 +668,183 @@ pub fn fetch(country_code: &str, url: &str) -> Result<(Vec<Cidr>, Vec<Cidr>)> {
         }
         retries += 1;
     }
-    from_buffer(&mut buffer, country_code)
+    Ok(buffer)
 }
 
-pub fn from_file(country_code: &str, path: PathBuf) -> Result<(Vec<Cidr>, Vec<Cidr>)> {
+fn load(path: PathBuf) -> Result<Vec<u8>> {
     let mut buffer = Vec::new();
     std::fs::File::open(path)?.read_to_end(&mut buffer)?;
-    from_buffer(&mut buffer, country_code)
+    Ok(buffer)
+}
+
+pub fn fetch(country_code: &str, url: &str, options: &Options) -> Result<(Vec<Cidr>, Vec<Cidr>)> {
+    from_buffer(&mut download(url)?, country_code, options)
+}
+
+pub fn from_file(
+    country_code: &str,
+    path: PathBuf,
+    options: &Options,
+) -> Result<(Vec<Cidr>, Vec<Cidr>)> {
+    from_buffer(&mut load(path)?, country_code, options)
+}
+
+/// Every country of a geoip.dat, for outputs holding several countries.
+fn countries_from_buffer(buffer: &[u8]) -> Result<Vec<(Box<str>, Vec<Cidr>)>> {
+    let geoip_list = GeoIpList::decode(buffer)
+        .context("Failed to decode GeoIpList, reading every country needs a geoip.dat")?;
+    Ok(geoip_list
+        .entry
+        .into_iter()
+        .map(|geoip| (geoip.country_code.into_boxed_str(), geoip.cidr))
+        .collect())
+}
+
+pub fn fetch_countries(url: &str) -> Result<Vec<(Box<str>, Vec<Cidr>)>> {
+    countries_from_buffer(&download(url)?)
+}
+
+pub fn countries_from_file(path: PathBuf) -> Result<Vec<(Box<str>, Vec<Cidr>)>> {
+    countries_from_buffer(&load(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        from_bgpdump, from_delegated, from_lines, from_mrs, from_mrt, is_delegated, is_mrt, Options,
+    };
+    use crate::{cidr, convert, generate::ToString as _, geoip::Cidr};
+
+    const DELEGATED: &str = include_str!("../tests/fixtures/delegated.txt");
+    /// A peer index table, then RIB records originated by AS4134 unless noted:
+    /// 1.0.1.0/24, 8.8.8.0/24 (AS15169), 2.0.0.0/16 (AS set {4809,4134}),
+    /// 2001:db8::/32, 2001:db9::/32 (AS15169), and the ADDPATH records
+    /// 1.0.0.0/24, 240e::/20.
+    const MRT: &[u8] = include_bytes!("../tests/fixtures/rib.mrt");
+
+    fn strings(cidrs: &[Cidr]) -> Vec<String> {
+        cidrs.iter().map(|cidr| cidr.to_string().unwrap()).collect()
+    }
+
+    fn asns(asns: &[u32]) -> Options {
+        Options {
+            asns: asns.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    fn statuses(statuses: &[&str]) -> Options {
+        Options {
+            statuses: statuses.iter().map(|&status| Box::from(status)).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn delegated_is_detected_past_comments() {
+        assert!(is_delegated(DELEGATED));
+        assert!(!is_delegated("# comment\n1.0.1.0/24\n"));
+    }
+
+    #[test]
+    fn delegated_skips_header_and_summary() {
+        let (ipv4, ipv6) = from_delegated(DELEGATED, "au", &statuses(&["assigned"])).unwrap();
+        assert_eq!(strings(&ipv4), ["1.0.0.0/24"]);
+        assert!(ipv6.is_empty());
+    }
+
+    #[test]
+    fn delegated_splits_counts_and_reads_prefix_lengths() {
+        let options = statuses(&["allocated", "assigned"]);
+        let (ipv4, ipv6) = from_delegated(DELEGATED, "CN", &options).unwrap();
+        // 768 addresses from 1.0.8.0 are a /23 and a /24.
+        assert_eq!(
+            strings(&ipv4),
+            [
+                "1.0.1.0/24",
+                "1.0.2.0/23",
+                "1.0.8.0/23",
+                "1.0.10.0/24",
+                "1.3.0.0/22"
+            ]
+        );
+        // Two adjacent /35 merge, the `available` record is not selected.
+        assert_eq!(strings(&ipv6), ["2001:250::/34"]);
+    }
+
+    #[test]
+    fn delegated_filters_by_status() {
+        let (ipv4, ipv6) = from_delegated(DELEGATED, "CN", &statuses(&["reserved"])).unwrap();
+        assert_eq!(strings(&ipv4), ["1.2.0.0/24"]);
+        assert!(ipv6.is_empty());
+
+        let (ipv4, ipv6) = from_delegated(DELEGATED, "CN", &statuses(&["Available"])).unwrap();
+        assert!(ipv4.is_empty());
+        assert_eq!(strings(&ipv6), ["2001:db8::/32"]);
+    }
+
+    #[test]
+    fn lines_take_cidrs_and_ranges() {
+        let text = "# comment\n10.0.0.0/24\n1.0.1.0-1.0.3.255 # range\n2001:db8::-2001:db8::ff\n";
+        let (ipv4, ipv6) = from_lines(text.lines()).unwrap();
+        assert_eq!(strings(&ipv4), ["10.0.0.0/24", "1.0.1.0/24", "1.0.2.0/23"]);
+        assert_eq!(strings(&ipv6), ["2001:db8::/120"]);
+    }
+
+    #[test]
+    fn ranges_round_trip_through_text() {
+        let text = "1.0.1.0/24\n1.0.2.0/23\n10.0.0.3/32\n10.0.0.4/30\n240e::/20\n";
+        let cidr_pair = from_lines(text.lines()).unwrap();
+        let ranges = String::from_utf8(convert::to_ranges(cidr_pair).unwrap().into()).unwrap();
+        assert_eq!(
+            ranges,
+            "1.0.1.0-1.0.3.255\n10.0.0.3-10.0.0.7\n240e::-240e:fff:ffff:ffff:ffff:ffff:ffff:ffff\n"
+        );
+        let (ipv4, ipv6) = from_lines(ranges.lines()).unwrap();
+        let cidr_pair = (
+            cidr::aggregate(&ipv4).unwrap(),
+            cidr::aggregate(&ipv6).unwrap(),
+        );
+        let round_trip = String::from_utf8(convert::to_text(cidr_pair).unwrap().into()).unwrap();
+        assert_eq!(round_trip, text);
+    }
+
+    #[test]
+    fn mrs_round_trip() {
+        // 10.0.0.3-10.0.0.7 is stored as one range but is no single CIDR.
+        let text = "1.0.1.0/24\n1.0.2.0/23\n10.0.0.3/32\n10.0.0.4/30\n2001:db8::/48\n240e::/20\n";
+        let cidr_pair = from_lines(text.lines()).unwrap();
+        let mrs = convert::to_mrs(cidr_pair).unwrap();
+        let (ipv4, ipv6) = from_mrs(&mrs).unwrap();
+        assert_eq!(
+            strings(&ipv4),
+            ["1.0.1.0/24", "1.0.2.0/23", "10.0.0.3/32", "10.0.0.4/30"]
+        );
+        assert_eq!(strings(&ipv6), ["2001:db8::/48", "240e::/20"]);
+    }
+
+    #[test]
+    fn mrt_selects_by_origin() {
+        assert!(is_mrt(MRT));
+        let (ipv4, ipv6) = from_mrt(MRT, &asns(&[4134])).unwrap();
+        assert_eq!(strings(&ipv4), ["1.0.0.0/23", "2.0.0.0/16"]);
+        assert_eq!(strings(&ipv6), ["2001:db8::/32", "240e::/20"]);
+        let (ipv4, ipv6) = from_mrt(MRT, &asns(&[15169])).unwrap();
+        assert_eq!(strings(&ipv4), ["8.8.8.0/24"]);
+        assert_eq!(strings(&ipv6), ["2001:db9::/32"]);
+    }
+
+    #[test]
+    fn bgpdump_selects_by_origin() {
+        let text = "\
+TABLE_DUMP2|1700000000|B|192.0.2.1|65000|1.0.1.0/24|65000 4134|IGP
+TABLE_DUMP2|1700000000|B|192.0.2.1|65000|2.0.0.0/16|65000 {4809,4134}|IGP
+TABLE_DUMP2|1700000000|B|192.0.2.1|65000|8.8.8.0/24|65000 15169|IGP
+TABLE_DUMP2|1700000000|B|192.0.2.1|65000|9.9.9.0/24|65000 [65001 65002]|IGP
+TABLE_DUMP2|1700000000|B|2001:db8::1|65000|240e::/20|65000 4134|IGP
+";
+        let (ipv4, ipv6) = from_bgpdump(text, &asns(&[4134])).unwrap();
+        assert_eq!(strings(&ipv4), ["1.0.1.0/24", "2.0.0.0/16"]);
+        assert_eq!(strings(&ipv6), ["240e::/20"]);
+    }
 }
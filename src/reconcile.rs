@@ -0,0 +1,296 @@
+use crate::{
+    cidr::{self, Range},
+    generate::{ToString as _, PROTOCOL, PROTOCOL_NAME},
+    geoip::Cidr,
+};
+use anyhow::{bail, Context as _, Ok, Result};
+use serde_json::Value;
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Write as _,
+    fs,
+};
+
+const RULE_KEYS: &[&str] = &["priority", "src", "dst", "dstlen", "table", "protocol"];
+
+type Key = (usize, Vec<u8>, u32);
+
+fn key(cidr: &Cidr) -> Key {
+    (cidr.ip.len(), cidr.ip.clone(), cidr.prefix)
+}
+
+fn load(paths: &[Box<str>]) -> Result<Vec<Value>> {
+    let mut values = Vec::new();
+    for path in paths {
+        let content = fs::read_to_string(&**path)?;
+        match serde_json::from_str(&content).with_context(|| format!("Failed to parse {}", path))? {
+            Value::Array(entries) => values.extend(entries),
+            value => values.push(value),
+        }
+    }
+    Ok(values)
+}
+
+fn table_id(table: &str) -> &str {
+    match table {
+        "default" => "253",
+        "main" => "254",
+        "local" => "255",
+        _ => table,
+    }
+}
+
+/// `ip -j route show table <table>` omits the table, entries without one are taken as ours.
+fn same_table(entry: &Value, table: &str) -> bool {
+    entry
+        .get("table")
+        .and_then(Value::as_str)
+        .is_none_or(|t| table_id(t) == table_id(table))
+}
+
+/// Whether a dumped route or rule carries [`PROTOCOL`], i.e. was installed by us.
+fn ours(entry: &Value) -> bool {
+    entry
+        .get("protocol")
+        .and_then(Value::as_str)
+        .is_some_and(|protocol| {
+            protocol == PROTOCOL_NAME || protocol.parse() == std::result::Result::Ok(PROTOCOL)
+        })
+}
+
+fn ip_command(cidr: &Cidr) -> &'static str {
+    if cidr.ip.len() == 4 {
+        "ip"
+    } else {
+        "ip -6"
+    }
+}
+
+/// Diffs against `ip -j route show table <table>` dumps, routes are matched by
+/// destination and updated in place when only the gateway or device differs.
+/// Only routes carrying [`PROTOCOL`] are touched, a foreign route to a wanted
+/// destination is left alone with a warning.
+pub fn iproute2route(
+    cidr_pair: (Vec<Cidr>, Vec<Cidr>),
+    current: &[Box<str>],
+    table: &str,
+    ipv4_gateway: &str,
+    ipv6_gateway: &str,
+    dev: &str,
+) -> Result<Box<str>> {
+    let (ipv4_cidrs, ipv6_cidrs) = cidr_pair;
+    let mut existing: BTreeMap<Key, (Cidr, Box<str>, Box<str>, bool)> = BTreeMap::new();
+    for entry in load(current)? {
+        let (Some(dst), Some(gateway)) = (
+            entry.get("dst").and_then(Value::as_str),
+            entry.get("gateway").and_then(Value::as_str),
+        ) else {
+            continue;
+        };
+        if dst == "default" || !same_table(&entry, table) {
+            continue;
+        }
+        let elem = cidr::parse(dst)?;
+        let entry_dev = entry.get("dev").and_then(Value::as_str).unwrap_or_default();
+        existing.insert(
+            key(&elem),
+            (elem, Box::from(gateway), Box::from(entry_dev), ours(&entry)),
+        );
+    }
+
+    let mut script = String::new();
+    for (elems, gateway) in [(&ipv4_cidrs, ipv4_gateway), (&ipv6_cidrs, ipv6_gateway)] {
+        for elem in elems {
+            let action = match existing.remove(&key(elem)) {
+                None => "add",
+                Some((_, g, d, true)) if *g == *gateway && *d == *dev => continue,
+                Some((.., true)) => "replace",
+                Some((.., false)) => {
+                    eprintln!(
+                        "Skipping {} in table {}: route not installed by tsumugi",
+                        elem.to_string()?,
+                        table
+                    );
+                    continue;
+                }
+            };
+            writeln!(
+                script,
+                "{} route {} table {} {} via {} dev {} proto {}",
+                ip_command(elem),
+                action,
+                table,
+                elem.to_string()?,
+                gateway,
+                dev,
+                PROTOCOL
+            )?;
+        }
+    }
+    for (elem, gateway, dev, _) in existing.into_values().filter(|(.., ours)| *ours) {
+        write!(
+            script,
+            "{} route delete table {} {} via {} proto {}",
+            ip_command(&elem),
+            table,
+            elem.to_string()?,
+            gateway,
+            PROTOCOL
+        )?;
+        if dev.is_empty() {
+            writeln!(script)?;
+        } else {
+            writeln!(script, " dev {}", dev)?;
+        }
+    }
+    Ok(script.into_boxed_str())
+}
+
+/// Diffs against `ip -j rule show` / `ip -6 -j rule show` dumps, only plain
+/// `to <cidr> lookup <table>` rules carrying [`PROTOCOL`] are considered ours.
+pub fn iproute2rule(
+    cidr_pair: (Vec<Cidr>, Vec<Cidr>),
+    current: &[Box<str>],
+    table: &str,
+) -> Result<Box<str>> {
+    let (ipv4_cidrs, ipv6_cidrs) = cidr_pair;
+    let mut existing: BTreeMap<Key, (Cidr, usize)> = BTreeMap::new();
+    for entry in load(current)? {
+        let Some(object) = entry.as_object() else {
+            continue;
+        };
+        let Some(dst) = entry.get("dst").and_then(Value::as_str) else {
+            continue;
+        };
+        if object.keys().any(|k| !RULE_KEYS.contains(&k.as_str()))
+            || entry.get("src").and_then(Value::as_str).unwrap_or("all") != "all"
+            || !same_table(&entry, table)
+            || !ours(&entry)
+        {
+            continue;
+        }
+        let elem = match entry.get("dstlen").and_then(Value::as_u64) {
+            Some(len) => cidr::parse(&format!("{}/{}", dst, len))?,
+            None => cidr::parse(dst)?,
+        };
+        existing.entry(key(&elem)).or_insert((elem, 0)).1 += 1;
+    }
+
+    let mut script = String::new();
+    for elem in ipv4_cidrs.iter().chain(&ipv6_cidrs) {
+        match existing.get_mut(&key(elem)) {
+            Some((_, count)) if *count > 0 => *count -= 1,
+            _ => writeln!(
+                script,
+                "{} rule add to {} lookup {} protocol {}",
+                ip_command(elem),
+                elem.to_string()?,
+                table,
+                PROTOCOL
+            )?,
+        }
+    }
+    for (elem, count) in existing.into_values() {
+        for _ in 0..count {
+            writeln!(
+                script,
+                "{} rule delete to {} lookup {} protocol {}",
+                ip_command(&elem),
+                elem.to_string()?,
+                table,
+                PROTOCOL
+            )?;
+        }
+    }
+    Ok(script.into_boxed_str())
+}
+
+fn nft_elem(elem: &Value) -> Result<Range> {
+    if let Some(addr) = elem.as_str() {
+        return Range::from_cidr(&cidr::parse(addr)?);
+    }
+    if let Some(prefix) = elem.get("prefix") {
+        let (Some(addr), Some(len)) = (
+            prefix.get("addr").and_then(Value::as_str),
+            prefix.get("len").and_then(Value::as_u64),
+        ) else {
+            bail!("Invalid prefix element: {}", prefix);
+        };
+        return Range::from_cidr(&cidr::parse(&format!("{}/{}", addr, len))?);
+    }
+    if let Some([start, last]) = elem
+        .get("range")
+        .and_then(Value::as_array)
+        .map(Vec::as_slice)
+    {
+        let (Some(start), Some(last)) = (start.as_str(), last.as_str()) else {
+            bail!("Invalid range element: {}", elem);
+        };
+        return Range::from_addrs(start.parse()?, last.parse()?);
+    }
+    if let Some(val) = elem.get("elem").and_then(|elem| elem.get("val")) {
+        return nft_elem(val);
+    }
+    bail!("Unsupported set element: {}", elem)
+}
+
+/// Diffs against `nft -j list table inet <table>` (or `list set`) dumps.
+///
+/// Elements are compared in merged form, as `nft` lists `auto-merge` sets.
+pub fn nftables(
+    cidr_pair: (Vec<Cidr>, Vec<Cidr>),
+    current: &[Box<str>],
+    table: &str,
+    ipv4set: &str,
+    ipv6set: &str,
+) -> Result<Box<str>> {
+    let (ipv4_cidrs, ipv6_cidrs) = cidr_pair;
+    let values = load(current)?;
+    let sets: Vec<&Value> = values
+        .iter()
+        .filter_map(|value| value.get("nftables").and_then(Value::as_array))
+        .flatten()
+        .filter_map(|item| item.get("set"))
+        .filter(|set| {
+            set.get("family").and_then(Value::as_str) == Some("inet")
+                && set.get("table").and_then(Value::as_str) == Some(table)
+        })
+        .collect();
+
+    let mut script = String::new();
+    for (set, elems) in [(ipv4set, &ipv4_cidrs), (ipv6set, &ipv6_cidrs)] {
+        let Some(current_set) = sets
+            .iter()
+            .find(|s| s.get("name").and_then(Value::as_str) == Some(set))
+        else {
+            bail!("Set inet {} {} not found in current state", table, set);
+        };
+        let existing = match current_set.get("elem").and_then(Value::as_array) {
+            Some(elems) => elems
+                .iter()
+                .map(nft_elem)
+                .collect::<Result<BTreeSet<_>>>()?,
+            None => BTreeSet::new(),
+        };
+        let desired: BTreeSet<Range> = cidr::merge(elems)?.into_iter().collect();
+
+        for (action, ranges) in [
+            ("delete", existing.difference(&desired)),
+            ("add", desired.difference(&existing)),
+        ] {
+            let ranges: Vec<String> = ranges.map(|range| range.to_string()).collect();
+            if !ranges.is_empty() {
+                writeln!(
+                    script,
+                    "{} element inet {} {} {{ {} }}",
+                    action,
+                    table,
+                    set,
+                    ranges.join(", ")
+                )?;
+            }
+        }
+    }
+    Ok(script.into_boxed_str())
+}
@@ -1,24 +1,47 @@
+use crate::{app::DEFAULT_STATUS, gateway::AUTO, read};
 use anyhow::Result;
 use which::which;
 
+/// `--code` and the source options, for the units to read the same set, e.g. `--code cn `.
+fn source_args(code: &str, options: &read::Options) -> String {
+    let mut args = String::new();
+    if !code.is_empty() {
+        args += &format!("--code {code} ");
+    }
+    if options.registered_country {
+        args += "--registered-country ";
+    }
+    if let Some(locations) = &options.locations {
+        args += &format!("--locations {locations} ");
+    }
+    let statuses = options.statuses.join(",");
+    if statuses != DEFAULT_STATUS {
+        args += &format!("--status {statuses} ");
+    }
+    if let Some(region) = &options.region {
+        args += &format!("--select-region {region} ");
+    }
+    if !options.asns.is_empty() {
+        let asns: Vec<String> = options.asns.iter().map(u32::to_string).collect();
+        args += &format!("--asn {} ", asns.join(","));
+    }
+    args
+}
+
 pub fn generate_nftables(
     url: &str,
     code: &str,
+    options: &read::Options,
     table: &str,
     ipv4set: &str,
     ipv6set: &str,
 ) -> Result<Box<str>> {
-    let nftables_exe = which("nft")?.to_string_lossy().into_owned();
     let current_exe = std::env::current_exe()?.to_string_lossy().into_owned();
-    let code_args = if !code.is_empty() {
-        &format!("--code {code} ")
-    } else {
-        ""
-    };
+    let source_args = source_args(code, options);
     Ok(format!(
         "\
 [Unit]
-Description=tsumugi nftables (nft -f)
+Description=tsumugi nftables (nf_tables netlink)
 Requires=nftables.service
 After=nftables.service
 # e.g. sing-box.service
@@ -28,18 +51,16 @@ After=place_holder.service
 [Service]
 Type=oneshot
 RemainAfterExit=yes
-ExecStart=/bin/sh -ec \"{current_exe} \\
-            --url {url} \\
-            {code_args}generate nftables \\
-            --table {table} --ipv4set {ipv4set} --ipv6set {ipv6set} | {nftables_exe} -f -\"
+ExecStart={current_exe} --url {url} \\
+            {source_args}apply nftables \\
+            --table {table} --ipv4set {ipv4set} --ipv6set {ipv6set}
 
-ExecReload=/bin/sh -ec \"{current_exe} \\
-            --url {url} \\
-            {code_args}generate nftables \\
-            --table {table} --ipv4set {ipv4set} --ipv6set {ipv6set} | {nftables_exe} -f -\"
+ExecReload={current_exe} --url {url} \\
+            {source_args}apply nftables \\
+            --table {table} --ipv4set {ipv4set} --ipv6set {ipv6set}
 
-ExecStop={nftables_exe} flush set inet {table} {ipv4set}
-ExecStop={nftables_exe} flush set inet {table} {ipv6set}
+ExecStop={current_exe} apply nftables \\
+            --flush --table {table} --ipv4set {ipv4set} --ipv6set {ipv6set}
 
 [Install]
 WantedBy=multi-user.target
@@ -51,6 +72,7 @@ WantedBy=multi-user.target
 pub fn generate_iproute2_route(
     url: &str,
     code: &str,
+    options: &read::Options,
     table: &str,
     ipv4_gateway: &str,
     ipv6_gateway: &str,
@@ -58,24 +80,28 @@ pub fn generate_iproute2_route(
 ) -> Result<Box<str>> {
     let ip_exe = which("ip")?.to_string_lossy().into_owned();
     let current_exe = std::env::current_exe()?.to_string_lossy().into_owned();
-    let code_args = if !code.is_empty() {
-        &format!("--code {code} ")
-    } else {
-        ""
-    };
+    let source_args = source_args(code, options);
     let cache_path = "/tmp/.tsumugi_iproute2_route_cache.db";
-    let orig_path = format!("{}.bak", cache_path);
-    let generate_args = "generate iproute2 route";
+    let current4_path = "/tmp/.tsumugi_iproute2_route_current4.json";
+    let current6_path = "/tmp/.tsumugi_iproute2_route_current6.json";
+    let apply_args = "apply iproute2 route";
+    let dump_table = format!("table {}", table);
     let table = format!("--table {}", table);
     let gateway_dev_args = format!(
         "--ipv4-gateway {} --ipv6-gateway {} --dev {}",
         ipv4_gateway, ipv6_gateway, dev
     );
+    // `auto` is resolved when the unit runs, so wait for the default route to exist.
+    let network_online = if [ipv4_gateway, ipv6_gateway, dev].contains(&AUTO) {
+        "Wants=network-online.target\nAfter=network-online.target\n"
+    } else {
+        ""
+    };
     Ok(format!(
         "\
 [Unit]
-Description=tsumugi iproute2 route (ip route add)
-# e.g. sing-box.service
+Description=tsumugi iproute2 route (rtnetlink)
+{network_online}# e.g. sing-box.service
 Requires=place_holder.service
 After=place_holder.service
 
@@ -84,21 +110,21 @@ Type=oneshot
 RemainAfterExit=yes
 
 ExecStartPre={current_exe} --url {url} \\
-            {code_args}convert --output {cache_path} srs
-ExecStart=/bin/sh -ec \"{current_exe} -f {cache_path} {generate_args} \\
-            {table} {gateway_dev_args}| {ip_exe} -batch -\"
-
-ExecReload=/bin/sh -ec \"/bin/mv {cache_path} {orig_path}; \\
-            {current_exe} -f {orig_path} {generate_args} \\
-            {current_exe} --url {url} \\
-            {code_args}convert --output {cache_path} srs; \\
-            --delete {table} {gateway_dev_args} | {ip_exe} -batch -; \\
-            /bin/rm {orig_path}; \\
-            {current_exe} {cache_path} {generate_args} \\
-            {table} {gateway_dev_args} | {ip_exe} -batch -\"
-
-ExecStop=/bin/sh -ec \"{current_exe} {cache_path} {generate_args} \\
-            --delete {table} {gateway_dev_args} | {ip_exe} -batch -\"
+            {source_args}convert --output {cache_path} srs
+ExecStart={current_exe} -f {cache_path} {apply_args} \\
+            {table} {gateway_dev_args}
+
+ExecReload=/bin/sh -ec \"{current_exe} --url {url} \\
+            {source_args}convert --output {cache_path} srs; \\
+            {ip_exe} -j route show {dump_table} > {current4_path}; \\
+            {ip_exe} -6 -j route show {dump_table} > {current6_path}; \\
+            {current_exe} -f {cache_path} generate \\
+            --current {current4_path} --current {current6_path} iproute2 route \\
+            {table} {gateway_dev_args} | /bin/sh -e; \\
+            /bin/rm -f {current4_path} {current6_path}\"
+
+ExecStop={current_exe} -f {cache_path} {apply_args} \\
+            --delete {table} {gateway_dev_args}
 ExecStop=/bin/rm -f {cache_path}
 
 [Install]
@@ -108,22 +134,24 @@ WantedBy=multi-user.target
     .into_boxed_str())
 }
 
-pub fn generate_iproute2_rule(url: &str, code: &str, table: &str) -> Result<Box<str>> {
+pub fn generate_iproute2_rule(
+    url: &str,
+    code: &str,
+    options: &read::Options,
+    table: &str,
+) -> Result<Box<str>> {
     let ip_exe = which("ip")?.to_string_lossy().into_owned();
     let current_exe = std::env::current_exe()?.to_string_lossy().into_owned();
-    let code_args = if !code.is_empty() {
-        &format!("--code {code} ")
-    } else {
-        ""
-    };
+    let source_args = source_args(code, options);
     let cache_path = "/tmp/.tsumugi_iproute2_rule_cache.db";
-    let orig_path = format!("{}.bak", cache_path);
-    let generate_args = "generate iproute2 rule";
+    let current4_path = "/tmp/.tsumugi_iproute2_rule_current4.json";
+    let current6_path = "/tmp/.tsumugi_iproute2_rule_current6.json";
+    let apply_args = "apply iproute2 rule";
     let table = format!("--table {}", table);
     Ok(format!(
         "\
 [Unit]
-Description=tsumugi iproute2 rule (ip rule add)
+Description=tsumugi iproute2 rule (rtnetlink)
 # e.g. sing-box.service
 Requires=place_holder.service
 After=place_holder.service
@@ -133,22 +161,22 @@ Type=oneshot
 RemainAfterExit=yes
 
 ExecStartPre={current_exe} --url {url} \\
-            {code_args}convert --output {cache_path} srs
-ExecStart=/bin/sh -ec \"{current_exe} -f {cache_path} {generate_args} \\
-            {table} | {ip_exe} -batch -\"
-
-ExecReload=/bin/sh -ec \"/bin/mv {cache_path} {orig_path}; \\
-            {current_exe} --url {url} \\
-            {code_args}convert --output {cache_path} srs; \\
-            {current_exe} -f {orig_path} {generate_args} \\
-            --delete {table} | {ip_exe} -batch -; \\
-            /bin/rm {orig_path}; \\
-            {current_exe} -f {cache_path} {generate_args} \\
-            {table} | {ip_exe} -batch -\"
-
-ExecStop=/bin/sh -ec \"{current_exe} -f {cache_path} {generate_args} \\
-            --delete {table} | {ip_exe} -batch -\"
-ExecStop=/bin/rm -f /tmp/.tsumugi_iproute2_rule_cache.db
+            {source_args}convert --output {cache_path} srs
+ExecStart={current_exe} -f {cache_path} {apply_args} \\
+            {table}
+
+ExecReload=/bin/sh -ec \"{current_exe} --url {url} \\
+            {source_args}convert --output {cache_path} srs; \\
+            {ip_exe} -j rule show > {current4_path}; \\
+            {ip_exe} -6 -j rule show > {current6_path}; \\
+            {current_exe} -f {cache_path} generate \\
+            --current {current4_path} --current {current6_path} iproute2 rule \\
+            {table} | /bin/sh -e; \\
+            /bin/rm -f {current4_path} {current6_path}\"
+
+ExecStop={current_exe} -f {cache_path} {apply_args} \\
+            --delete {table}
+ExecStop=/bin/rm -f {cache_path}
 
 [Install]
 WantedBy=multi-user.target
@@ -0,0 +1,83 @@
+//! The netns tests run `apply` in a fresh network namespace, they need `unshare`
+//! and unprivileged user namespaces: `cargo test -- --ignored`.
+
+mod common;
+
+use common::{netns, stdout, CIDRS, TSUMUGI};
+use std::process::Command;
+
+#[test]
+fn route_dry_run_without_device() {
+    let output = Command::new(TSUMUGI)
+        .args(["--file", CIDRS, "apply", "--dry-run", "iproute2", "route"])
+        .args([
+            "-t",
+            "100",
+            "-4",
+            "192.0.2.2",
+            "-6",
+            "2001:db8::2",
+            "-d",
+            "tsumugi0",
+        ])
+        .output()
+        .unwrap();
+    let plan = stdout(output);
+    assert!(plan.contains("route add table 100 198.51.100.0/24 via 192.0.2.2 dev tsumugi0"));
+    assert!(plan.contains("route add table 100 203.0.113.0/25 via 192.0.2.2 dev tsumugi0"));
+    assert!(plan.contains("route add table 100 2001:db8:1::/48 via 2001:db8::2 dev tsumugi0"));
+}
+
+#[test]
+#[ignore = "needs unshare and unprivileged user namespaces"]
+fn route_in_netns() {
+    let routes = stdout(netns(
+        r#"
+        ip link add tsumugi0 type bridge
+        ip link set tsumugi0 up
+        ip addr add 192.0.2.1/24 dev tsumugi0
+        ip -6 addr add 2001:db8::1/64 dev tsumugi0 nodad
+        "$TSUMUGI" --file "$CIDRS" apply iproute2 route -t 100 -4 192.0.2.2 -6 2001:db8::2 -d tsumugi0
+        ip route show table 100
+        ip -6 route show table 100
+        "#,
+    ));
+    assert!(routes.contains("198.51.100.0/24 via 192.0.2.2 dev tsumugi0"));
+    assert!(routes.contains("203.0.113.0/25 via 192.0.2.2 dev tsumugi0"));
+    assert!(routes.contains("2001:db8:1::/48 via 2001:db8::2 dev tsumugi0"));
+}
+
+#[test]
+#[ignore = "needs unshare and unprivileged user namespaces"]
+fn rule_in_netns() {
+    let rules = stdout(netns(
+        r#"
+        "$TSUMUGI" --file "$CIDRS" apply iproute2 rule -t 100
+        ip rule show
+        ip -6 rule show
+        "#,
+    ));
+    assert!(rules.contains("to 198.51.100.0/24 lookup 100"));
+    assert!(rules.contains("to 203.0.113.0/25 lookup 100"));
+    assert!(rules.contains("to 2001:db8:1::/48 lookup 100"));
+}
+
+#[test]
+#[ignore = "needs unshare and unprivileged user namespaces"]
+fn route_auto_takes_main_default_routes() {
+    let plan = stdout(netns(
+        r#"
+        ip link add tsumugi0 type bridge
+        ip link set tsumugi0 up
+        ip addr add 192.0.2.1/24 dev tsumugi0
+        ip -6 addr add 2001:db8::1/64 dev tsumugi0 nodad
+        ip route add default via 192.0.2.2
+        ip -6 route add default via 2001:db8::3 table 100
+        ip -6 route add default via 2001:db8::4 metric 50
+        ip -6 route add default via 2001:db8::2 metric 10
+        "$TSUMUGI" --file "$CIDRS" apply --dry-run iproute2 route -t 100 -4 auto -6 auto -d auto
+        "#,
+    ));
+    assert!(plan.contains("route add table 100 198.51.100.0/24 via 192.0.2.2 dev tsumugi0"));
+    assert!(plan.contains("route add table 100 2001:db8:1::/48 via 2001:db8::2 dev tsumugi0"));
+}
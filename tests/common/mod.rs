@@ -0,0 +1,26 @@
+// Every test crate includes this module and uses a different part of it.
+#![allow(dead_code)]
+
+use std::process::{Command, Output};
+
+pub const TSUMUGI: &str = env!("CARGO_BIN_EXE_tsumugi");
+pub const CIDRS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/cidrs.txt");
+
+/// Runs `script` with `$TSUMUGI` and `$CIDRS` set, in a new user and network namespace.
+pub fn netns(script: &str) -> Output {
+    Command::new("unshare")
+        .args(["-rn", "sh", "-ec", script])
+        .env("TSUMUGI", TSUMUGI)
+        .env("CIDRS", CIDRS)
+        .output()
+        .unwrap()
+}
+
+pub fn stdout(output: Output) -> String {
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
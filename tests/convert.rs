@@ -0,0 +1,57 @@
+mod common;
+
+use common::{stdout, TSUMUGI};
+use std::{fs, process::Command};
+
+const GEOFEED: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/geofeed.csv");
+
+/// Runs `tsumugi --file <input> <args> convert --output <tmp> <command>`, returns the output.
+fn convert(input: &str, args: &[&str], command: &[&str]) -> String {
+    let output = std::env::temp_dir().join(format!(
+        "tsumugi-test-{}-{}",
+        std::process::id(),
+        command.join("-")
+    ));
+    stdout(
+        Command::new(TSUMUGI)
+            .args(["--file", input])
+            .args(args)
+            .args(["convert", "--output"])
+            .arg(&output)
+            .args(command)
+            .output()
+            .unwrap(),
+    );
+    let converted = fs::read_to_string(&output).unwrap();
+    fs::remove_file(&output).unwrap();
+    converted
+}
+
+#[test]
+fn geofeed_retags_region() {
+    let geofeed = convert(
+        GEOFEED,
+        &["--code", "CN"],
+        &[
+            "geofeed",
+            "--set-region",
+            "CN-GD",
+            "--set-city",
+            "Guangzhou",
+        ],
+    );
+    assert_eq!(
+        geofeed,
+        "192.0.2.0/24,CN,CN-GD,Guangzhou,\n198.51.100.0/24,CN,CN-GD,Guangzhou,\n"
+    );
+}
+
+#[test]
+fn geofeed_selects_and_retags_region() {
+    let geofeed = convert(
+        GEOFEED,
+        &["--code", "CN", "--select-region", "CN-BJ"],
+        &["geofeed", "--set-region", "CN-SH"],
+    );
+    assert_eq!(geofeed, "192.0.2.0/25,CN,CN-SH,,\n");
+}
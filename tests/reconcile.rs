@@ -0,0 +1,86 @@
+//! Reloads diff against `ip -j` dumps and must only touch what tsumugi installed.
+//! Needs `unshare` and unprivileged user namespaces: `cargo test -- --ignored`.
+
+mod common;
+
+use common::{netns, stdout};
+
+/// Same as the systemd unit's `ExecReload`.
+const RELOAD_ROUTES: &str = r#"
+    ip -j route show table main > "$DUMP"
+    ip -6 -j route show table main > "$DUMP6"
+    "$TSUMUGI" --file "$CIDRS" generate --current "$DUMP" --current "$DUMP6" iproute2 route \
+        -t main -4 192.0.2.2 -6 2001:db8::2 -d tsumugi0 | sh -e
+"#;
+
+const RELOAD_RULES: &str = r#"
+    ip -j rule show > "$DUMP"
+    ip -6 -j rule show > "$DUMP6"
+    "$TSUMUGI" --file "$CIDRS" generate --current "$DUMP" --current "$DUMP6" iproute2 rule \
+        -t 100 | sh -e
+"#;
+
+#[test]
+#[ignore = "needs unshare and unprivileged user namespaces"]
+fn route_reload_keeps_foreign_routes() {
+    let routes = stdout(netns(&format!(
+        r#"
+        DUMP=$(mktemp) DUMP6=$(mktemp)
+        ip link add tsumugi0 type bridge
+        ip link set tsumugi0 up
+        ip addr add 192.0.2.1/24 dev tsumugi0
+        ip -6 addr add 2001:db8::1/64 dev tsumugi0 nodad
+        ip route add 192.0.2.128/25 via 192.0.2.2 dev tsumugi0
+        ip route add 198.18.0.0/15 via 192.0.2.2 dev tsumugi0 proto 200
+        ip route add 203.0.113.0/25 via 192.0.2.3 dev tsumugi0
+        {RELOAD_ROUTES}
+        {RELOAD_ROUTES}
+        rm -f "$DUMP" "$DUMP6"
+        ip route show table main
+        ip -6 route show table main
+        "#
+    )));
+    assert!(routes.contains("192.0.2.128/25 via 192.0.2.2 dev tsumugi0"));
+    assert!(!routes.contains("198.18.0.0/15"));
+    assert!(routes.contains("198.51.100.0/24 via 192.0.2.2 dev tsumugi0 proto 200"));
+    assert!(routes.contains("203.0.113.0/25 via 192.0.2.3 dev tsumugi0"));
+    assert!(!routes.contains("203.0.113.0/25 via 192.0.2.2"));
+    assert!(routes.contains("2001:db8:1::/48 via 2001:db8::2 dev tsumugi0 proto 200"));
+}
+
+#[test]
+#[ignore = "needs unshare and unprivileged user namespaces"]
+fn rule_reload_keeps_foreign_rules() {
+    let rules = stdout(netns(&format!(
+        r#"
+        DUMP=$(mktemp) DUMP6=$(mktemp)
+        ip rule add to 9.9.9.0/24 lookup 100 priority 100
+        ip rule add to 9.9.8.0/24 lookup 100 protocol 200
+        {RELOAD_RULES}
+        {RELOAD_RULES}
+        rm -f "$DUMP" "$DUMP6"
+        ip rule show
+        ip -6 rule show
+        "#
+    )));
+    assert!(rules.contains("100:\tfrom all to 9.9.9.0/24 lookup 100"));
+    assert!(!rules.contains("9.9.8.0/24"));
+    assert_eq!(
+        rules
+            .matches("to 198.51.100.0/24 lookup 100 proto 200")
+            .count(),
+        1
+    );
+    assert_eq!(
+        rules
+            .matches("to 203.0.113.0/25 lookup 100 proto 200")
+            .count(),
+        1
+    );
+    assert_eq!(
+        rules
+            .matches("to 2001:db8:1::/48 lookup 100 proto 200")
+            .count(),
+        1
+    );
+}